@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_method;
+ALTER TABLE handler_events DROP COLUMN query;
+ALTER TABLE handler_events DROP COLUMN headers;
+ALTER TABLE handler_events DROP COLUMN http_version;
+ALTER TABLE handler_events DROP COLUMN method;
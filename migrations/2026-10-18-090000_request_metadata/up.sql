@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE handler_events ADD COLUMN method VARCHAR;
+ALTER TABLE handler_events ADD COLUMN http_version VARCHAR;
+ALTER TABLE handler_events ADD COLUMN headers JSONB;
+ALTER TABLE handler_events ADD COLUMN query JSONB;
+CREATE INDEX idx_method ON handler_events(method);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_payload_sha256;
+ALTER TABLE handler_events DROP COLUMN payload_sha256;
+ALTER TABLE handler_events DROP COLUMN payload_truncated;
+ALTER TABLE handler_events DROP COLUMN payload_size;
+ALTER TABLE handler_events DROP COLUMN payload_raw;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE handler_events ADD COLUMN payload_raw BYTEA;
+ALTER TABLE handler_events ADD COLUMN payload_size BIGINT;
+ALTER TABLE handler_events ADD COLUMN payload_truncated BOOLEAN;
+ALTER TABLE handler_events ADD COLUMN payload_sha256 VARCHAR(64);
+CREATE INDEX idx_payload_sha256 ON handler_events(payload_sha256);
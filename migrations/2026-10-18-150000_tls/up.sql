@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE handler_events ADD COLUMN tls_sni VARCHAR;
+ALTER TABLE handler_events ADD COLUMN tls_alpn VARCHAR;
+ALTER TABLE handler_events ADD COLUMN tls_version VARCHAR;
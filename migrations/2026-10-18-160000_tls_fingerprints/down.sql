@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE handler_events DROP COLUMN tls_ja4;
+ALTER TABLE handler_events DROP COLUMN tls_ja3;
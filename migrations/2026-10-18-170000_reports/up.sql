@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS reports (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL,
+    ip VARCHAR NOT NULL,
+    categories VARCHAR NOT NULL,
+    comment VARCHAR,
+    status VARCHAR(16) NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP NOT NULL,
+    http_status INTEGER,
+    response VARCHAR
+);
+CREATE INDEX idx_reports_ip_created_at ON reports(ip, created_at);
+CREATE INDEX idx_reports_status ON reports(status);
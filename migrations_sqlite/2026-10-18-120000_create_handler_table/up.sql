@@ -0,0 +1,26 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS handler_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    handler VARCHAR(255) NOT NULL,
+    subhandler VARCHAR,
+    host VARCHAR,
+    uri VARCHAR,
+    raw_uri VARCHAR,
+    src_ip VARCHAR,
+    payload VARCHAR,
+    user_agent VARCHAR,
+    handler_data VARCHAR,
+    x_forwarded_for VARCHAR,
+    method VARCHAR,
+    http_version VARCHAR,
+    headers TEXT,
+    query TEXT,
+    payload_raw BLOB,
+    payload_size BIGINT,
+    payload_truncated BOOLEAN,
+    payload_sha256 VARCHAR(64)
+);
+CREATE INDEX idx_subhandler ON handler_events(subhandler);
+CREATE INDEX idx_method ON handler_events(method);
+CREATE INDEX idx_payload_sha256 ON handler_events(payload_sha256);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE handler_events ADD COLUMN ip_source VARCHAR;
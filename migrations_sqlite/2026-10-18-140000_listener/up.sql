@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE handler_events ADD COLUMN listener VARCHAR;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE handler_events DROP COLUMN tls_version;
+ALTER TABLE handler_events DROP COLUMN tls_alpn;
+ALTER TABLE handler_events DROP COLUMN tls_sni;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE handler_events ADD COLUMN tls_ja3 VARCHAR;
+ALTER TABLE handler_events ADD COLUMN tls_ja4 VARCHAR;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE reports ADD COLUMN retry_at TIMESTAMP;
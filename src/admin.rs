@@ -0,0 +1,107 @@
+use crate::configuration::{self, get_settings, AdminConfig};
+use crate::handler;
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use log::{error, info};
+
+// Configuration reloads, triggered by SIGHUP or by a request to the admin
+// listener
+
+/// Re-reads the configuration file and the environment, and replaces the
+/// settings and the configured handlers. Nothing is replaced unless the new
+/// configuration is entirely valid.
+///
+/// Listeners, sinks and the database connection are set up at startup, so
+/// changes to them only take effect after a restart.
+pub fn reload(config_path: &str) -> Result<(), Vec<String>> {
+    let config = configuration::read_config(config_path).map_err(|e| vec![e])?;
+    let settings = configuration::parse_settings(&config)?;
+    let (registry, handler_count) = handler::build_registry(&settings).map_err(|e| vec![e])?;
+
+    handler::publish(settings, registry);
+    info!(
+        "Reloaded configuration from {} ({} handlers from configuration)",
+        config_path, handler_count
+    );
+    Ok(())
+}
+
+fn log_reload_errors(errors: &[String]) {
+    for e in errors {
+        error!(
+            "Failed to reload configuration, keeping the current one: {}",
+            e
+        );
+    }
+}
+
+pub async fn reload_on_sighup(config_path: String) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+        if let Err(errors) = reload(&config_path) {
+            log_reload_errors(&errors);
+        }
+    }
+}
+
+/// Compares two byte strings in a time that depends on their length only, so
+/// that timing requests doesn't reveal how much of a guessed token is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (x, y)| difference | (x ^ y));
+    std::hint::black_box(difference) == 0
+}
+
+async fn reload_handler(req: HttpRequest, config_path: web::Data<String>) -> impl Responder {
+    let settings = get_settings();
+    if let Some(token) = settings
+        .admin
+        .as_ref()
+        .and_then(|admin| admin.token.as_ref())
+    {
+        let authorized = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| constant_time_eq(value.as_bytes(), token.as_bytes()));
+        if !authorized {
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    info!("Reload requested through the admin listener");
+    match reload(&config_path) {
+        Ok(()) => HttpResponse::Ok().body("Configuration reloaded\n"),
+        Err(errors) => {
+            log_reload_errors(&errors);
+            HttpResponse::UnprocessableEntity().body(format!("{}\n", errors.join("\n")))
+        }
+    }
+}
+
+/// Starts the admin listener, which serves `POST /reload`
+pub fn start_server(admin_config: &AdminConfig, config_path: String) -> std::io::Result<()> {
+    let config_path = web::Data::new(config_path);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(config_path.clone())
+            .route("/reload", web::post().to(reload_handler))
+    })
+    .workers(1)
+    .bind((admin_config.host.as_str(), admin_config.port))?
+    .run();
+    Ok(())
+}
@@ -0,0 +1,323 @@
+use crate::configuration::AllowlistConfig;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::system_conf::read_system_conf;
+use trust_dns_resolver::TokioAsyncResolver;
+
+// Addresses never reported (`reporting.allowlist`): allowlisted networks,
+// and search engine crawlers. Crawlers are verified by forward-confirmed
+// reverse DNS: the PTR record of the address names a host under one of the
+// crawler domains, and that host resolves back to the address. Anyone can
+// set the PTR record of their own address, but not the records of
+// googlebot.com.
+
+/// How long a DNS server may take to answer, so an unresponsive one holds
+/// the reporter back for seconds, not minutes
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a failed verification is cached, so a DNS outage isn't hit
+/// again for every report
+const FAILURE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Checks reports against the allowlist. Only used by the reporter thread.
+pub struct Allowlist {
+    /// Resolver, and the name servers it was created with
+    resolver: Option<(Vec<SocketAddr>, TokioAsyncResolver)>,
+    /// Verified crawler host name of addresses, None if they aren't crawlers
+    /// or couldn't be verified, with the time the verification expires
+    verified: HashMap<IpAddr, (Instant, Option<String>)>,
+    last_prune: Instant,
+}
+
+impl Allowlist {
+    pub fn new() -> Self {
+        Allowlist {
+            resolver: None,
+            verified: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Why `ip` must not be reported, if it is allowlisted
+    pub async fn check(&mut self, ip: &str, config: &AllowlistConfig) -> Option<String> {
+        let ip = ip.parse::<IpAddr>().ok()?;
+        if let Some(network) = config.networks.iter().find(|network| network.contains(ip)) {
+            return Some(format!("in allowlisted network {}", network));
+        }
+        if !config.verify_crawlers || config.crawler_domains.is_empty() {
+            return None;
+        }
+        self.crawler(ip, config)
+            .await
+            .map(|host| format!("verified crawler {}", host))
+    }
+
+    /// Host name of the crawler at `ip`, cached for `cache-ttl`, or for
+    /// `FAILURE_TTL` if it couldn't be verified
+    async fn crawler(&mut self, ip: IpAddr, config: &AllowlistConfig) -> Option<String> {
+        let ttl = Duration::from_secs(config.cache_ttl);
+        self.prune(ttl.min(FAILURE_TTL));
+        if let Some((expires, host)) = self.verified.get(&ip) {
+            if Instant::now() < *expires {
+                return host.clone();
+            }
+        }
+
+        let resolver = self.resolver(&config.nameservers).await?;
+        let (host, ttl) = match verify(resolver, ip, &config.crawler_domains).await {
+            Ok(host) => (host, ttl),
+            Err(e) => {
+                warn!("Failed to verify whether {} is a crawler: {}", ip, e);
+                (None, ttl.min(FAILURE_TTL))
+            }
+        };
+        self.verified
+            .insert(ip, (Instant::now() + ttl, host.clone()));
+        host
+    }
+
+    /// Resolver for `nameservers`, or the system resolver if there are none.
+    /// It is created again when they change.
+    async fn resolver(&mut self, nameservers: &[SocketAddr]) -> Option<&TokioAsyncResolver> {
+        let outdated = match &self.resolver {
+            Some((created_with, _)) => created_with != nameservers,
+            None => true,
+        };
+        if outdated {
+            let config = if nameservers.is_empty() {
+                read_system_conf().map_err(ResolveError::from)
+            } else {
+                let mut group = NameServerConfigGroup::new();
+                for nameserver in nameservers {
+                    group.merge(NameServerConfigGroup::from_ips_clear(
+                        &[nameserver.ip()],
+                        nameserver.port(),
+                    ));
+                }
+                Ok((
+                    ResolverConfig::from_parts(None, Vec::new(), group),
+                    ResolverOpts::default(),
+                ))
+            };
+            let resolver = match config {
+                Ok((config, opts)) => {
+                    let opts = ResolverOpts {
+                        timeout: LOOKUP_TIMEOUT,
+                        attempts: 1,
+                        ..opts
+                    };
+                    TokioAsyncResolver::tokio(config, opts).await
+                }
+                Err(e) => Err(e),
+            };
+            match resolver {
+                Ok(resolver) => self.resolver = Some((nameservers.to_vec(), resolver)),
+                Err(e) => {
+                    warn!("Failed to create DNS resolver: {}", e);
+                    return None;
+                }
+            }
+        }
+        self.resolver.as_ref().map(|(_, resolver)| resolver)
+    }
+
+    /// Forgets expired verifications, at most once per `interval`
+    fn prune(&mut self, interval: Duration) {
+        if self.last_prune.elapsed() < interval {
+            return;
+        }
+        let now = Instant::now();
+        self.last_prune = now;
+        self.verified.retain(|_, (expires, _)| now < *expires);
+    }
+}
+
+/// Host name of `ip` under one of `domains`, if it resolves back to `ip`
+async fn verify(
+    resolver: &TokioAsyncResolver,
+    ip: IpAddr,
+    domains: &[String],
+) -> Result<Option<String>, ResolveError> {
+    let names = match resolver.reverse_lookup(ip).await {
+        Ok(names) => names,
+        Err(e) if is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    for name in names.iter() {
+        let host = name.to_utf8().trim_end_matches('.').to_lowercase();
+        if !domains.iter().any(|domain| is_in_domain(&host, domain)) {
+            continue;
+        }
+        let fqdn = format!("{}.", host);
+        let addresses = match ip {
+            IpAddr::V4(_) => resolver
+                .ipv4_lookup(fqdn.as_str())
+                .await
+                .map(|lookup| lookup.iter().map(|a| IpAddr::V4(*a)).collect::<Vec<_>>()),
+            IpAddr::V6(_) => resolver
+                .ipv6_lookup(fqdn.as_str())
+                .await
+                .map(|lookup| lookup.iter().map(|a| IpAddr::V6(*a)).collect::<Vec<_>>()),
+        };
+        match addresses {
+            Ok(addresses) if addresses.contains(&ip) => return Ok(Some(host)),
+            Ok(_) => debug!("{} claims to be {}, which does not resolve to it", ip, host),
+            Err(e) if is_not_found(&e) => {
+                debug!("{} claims to be {}, which does not resolve", ip, host)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+fn is_not_found(error: &ResolveError) -> bool {
+    matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+/// Whether `host` is `domain` or one of its subdomains
+fn is_in_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_matches('.').to_lowercase();
+    match host.strip_suffix(domain.as_str()) {
+        Some(prefix) => prefix.is_empty() || prefix.ends_with('.'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::str::FromStr;
+    use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use trust_dns_resolver::proto::rr::{Name, RData, Record};
+
+    #[test]
+    fn matches_domains_and_subdomains_only() {
+        assert!(is_in_domain("googlebot.com", "googlebot.com"));
+        assert!(is_in_domain(
+            "crawl-66-249-66-1.googlebot.com",
+            "googlebot.com"
+        ));
+        assert!(is_in_domain("msnbot-1.search.msn.com", ".Search.MSN.com."));
+        assert!(!is_in_domain("notgooglebot.com", "googlebot.com"));
+        assert!(!is_in_domain("googlebot.com.example.net", "googlebot.com"));
+        assert!(!is_in_domain("msn.com", "search.msn.com"));
+    }
+
+    /// Stand-in for a DNS server, answering from `records`
+    fn name_server(records: Vec<(&'static str, RData)>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((len, client)) = socket.recv_from(&mut buf) {
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_authoritative(true)
+                    .add_query(query.clone());
+                let answers = records.iter().filter(|(name, rdata)| {
+                    Name::from_str(name).unwrap() == *query.name()
+                        && rdata.to_record_type() == query.query_type()
+                });
+                let mut found = false;
+                for (name, rdata) in answers {
+                    let name = Name::from_str(name).unwrap();
+                    response.add_answer(Record::from_rdata(name, 60, rdata.clone()));
+                    found = true;
+                }
+                if !found {
+                    response.set_response_code(ResponseCode::NXDomain);
+                }
+                socket.send_to(&response.to_vec().unwrap(), client).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn ptr(host: &str) -> RData {
+        RData::PTR(Name::from_str(host).unwrap())
+    }
+
+    fn config(nameserver: SocketAddr) -> AllowlistConfig {
+        AllowlistConfig {
+            networks: vec!["198.51.100.0/24".parse().unwrap()],
+            nameservers: vec![nameserver],
+            ..AllowlistConfig::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn verifies_crawlers_both_ways() {
+        let nameserver = name_server(vec![
+            ("9.0.0.10.in-addr.arpa.", ptr("crawl-1.googlebot.com.")),
+            (
+                "crawl-1.googlebot.com.",
+                RData::A(Ipv4Addr::new(10, 0, 0, 9)),
+            ),
+            // Claims to be a crawler, but the name resolves elsewhere
+            ("10.0.0.10.in-addr.arpa.", ptr("crawl-2.googlebot.com.")),
+            (
+                "crawl-2.googlebot.com.",
+                RData::A(Ipv4Addr::new(10, 0, 0, 99)),
+            ),
+            ("11.0.0.10.in-addr.arpa.", ptr("vm.googleusercontent.com.")),
+            (
+                "vm.googleusercontent.com.",
+                RData::A(Ipv4Addr::new(10, 0, 0, 11)),
+            ),
+        ]);
+        let config = config(nameserver);
+        let mut allowlist = Allowlist::new();
+
+        assert_eq!(
+            allowlist.check("10.0.0.9", &config).await.as_deref(),
+            Some("verified crawler crawl-1.googlebot.com")
+        );
+        assert_eq!(allowlist.check("10.0.0.10", &config).await, None);
+        assert_eq!(allowlist.check("10.0.0.11", &config).await, None);
+        assert_eq!(allowlist.check("10.0.0.12", &config).await, None);
+        assert_eq!(
+            allowlist.check("198.51.100.7", &config).await.as_deref(),
+            Some("in allowlisted network 198.51.100.0/24")
+        );
+        assert_eq!(
+            allowlist
+                .verified
+                .get(&"10.0.0.9".parse().unwrap())
+                .unwrap()
+                .1,
+            Some("crawl-1.googlebot.com".to_string())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn gives_up_quickly_on_unresponsive_name_servers() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = config(silent.local_addr().unwrap());
+        let mut allowlist = Allowlist::new();
+
+        let start = Instant::now();
+        assert_eq!(allowlist.check("10.0.0.9", &config).await, None);
+        assert!(
+            start.elapsed() < LOOKUP_TIMEOUT * 3,
+            "{:?}",
+            start.elapsed()
+        );
+
+        // The failure is cached for a short while only
+        let (expires, host) = allowlist.verified[&"10.0.0.9".parse().unwrap()].clone();
+        assert_eq!(host, None);
+        assert!(expires <= Instant::now() + FAILURE_TTL);
+        let start = Instant::now();
+        assert_eq!(allowlist.check("10.0.0.9", &config).await, None);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
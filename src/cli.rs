@@ -0,0 +1,307 @@
+use crate::configuration::{self, Settings};
+use crate::db::{self, queries};
+use crate::handler;
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::{Args, Parser, Subcommand};
+use log::{error, info};
+use std::io::{self, Write};
+
+// Command line interface. Every subcommand reads the configuration file
+// given with `--config`; output meant for the user goes to stdout, while
+// errors are logged.
+
+pub const DEFAULT_CONFIG: &str = "Config.toml";
+/// Number of events read from the database at once by `export`
+const EXPORT_PAGE_SIZE: i64 = 1000;
+
+#[derive(Parser)]
+#[command(version, about = "A simple HTTP honeypot")]
+pub struct Cli {
+    /// Runs the server when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the HTTP server
+    Serve(ConfigArgs),
+    /// Run the pending database migrations, regardless of `db.migrate`
+    Migrate(ConfigArgs),
+    /// Validate the configuration file and the handlers it defines
+    CheckConfig(CheckConfigArgs),
+    /// List the handlers, in the order they are matched
+    ListHandlers(ConfigArgs),
+    /// Print the stored events as JSON, one per line
+    Export(ExportArgs),
+    /// Summarize the stored events
+    Stats(StatsArgs),
+}
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    /// Path of the configuration file
+    #[arg(short, long, default_value = DEFAULT_CONFIG)]
+    pub config: String,
+}
+
+#[derive(Args)]
+pub struct CheckConfigArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Path of the configuration file, the form taken before `--config`
+    #[arg(conflicts_with = "config", hide = true)]
+    pub file: Option<String>,
+}
+
+impl CheckConfigArgs {
+    pub fn config_path(&self) -> &str {
+        self.file.as_deref().unwrap_or(&self.config.config)
+    }
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Only export events recorded at or after this date or time
+    /// (`YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`)
+    #[arg(long, value_parser = parse_timestamp)]
+    pub since: Option<NaiveDateTime>,
+    /// Only export events of this handler
+    #[arg(long)]
+    pub handler: Option<String>,
+    /// Maximum number of events to export
+    #[arg(long)]
+    pub limit: Option<i64>,
+}
+
+#[derive(Args)]
+pub struct StatsArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Only count events recorded at or after this date or time
+    /// (`YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`)
+    #[arg(long, value_parser = parse_timestamp)]
+    pub since: Option<NaiveDateTime>,
+    /// Number of source addresses to list
+    #[arg(long, default_value_t = 10)]
+    pub top: i64,
+}
+
+fn parse_timestamp(value: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_hms(0, 0, 0)))
+        .map_err(|_| format!("invalid date \"{}\"", value))
+}
+
+/// Reads and validates the settings, logging every problem found
+fn load_settings(config_path: &str) -> Option<Settings> {
+    match configuration::read_config(config_path)
+        .map_err(|e| vec![e])
+        .and_then(|config| configuration::parse_settings(&config))
+    {
+        Ok(settings) => Some(settings),
+        Err(errors) => {
+            for e in errors {
+                error!("{}", e);
+            }
+            None
+        }
+    }
+}
+
+/// Connects to the configured database, without running migrations
+fn connect(settings: &Settings) -> Option<db::DbConnection> {
+    match db::create_pool(&settings.db_config).get() {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            error!(
+                "Failed to connect to database {}: {}",
+                settings.db_config, e
+            );
+            None
+        }
+    }
+}
+
+/// Builds the handler registry, logging every problem found
+fn load_registry(settings: &Settings) -> Option<(handler::HandlerRegistry, usize)> {
+    match handler::build_registry(settings) {
+        Ok(loaded) => Some(loaded),
+        Err(e) => {
+            error!("Failed to load handlers: {}", e);
+            None
+        }
+    }
+}
+
+/// Validates a configuration file, including the handlers it defines, and
+/// logs every problem found. Returns whether the file is valid.
+pub fn check_config(config_path: &str) -> bool {
+    let handler_count = match load_settings(config_path).as_ref().and_then(load_registry) {
+        Some((_, handler_count)) => handler_count,
+        None => return false,
+    };
+
+    info!(
+        "Configuration file \"{}\" is valid ({} handlers defined)",
+        config_path, handler_count
+    );
+    true
+}
+
+pub fn migrate(config_path: &str) -> bool {
+    let conn = match load_settings(config_path).as_ref().and_then(connect) {
+        Some(conn) => conn,
+        None => return false,
+    };
+    match db::run_migrations(&conn) {
+        Ok(()) => {
+            info!("Migrations ran successfully");
+            true
+        }
+        Err(e) => {
+            error!("Error running migrations: {}", e);
+            false
+        }
+    }
+}
+
+pub fn list_handlers(config_path: &str) -> bool {
+    let registry = match load_settings(config_path).as_ref().and_then(load_registry) {
+        Some((registry, _)) => registry,
+        None => return false,
+    };
+
+    let name_width = registry
+        .handlers()
+        .iter()
+        .map(|handler| handler.name.len())
+        .max()
+        .unwrap_or(0);
+    let priority_width = registry
+        .handlers()
+        .iter()
+        .map(|handler| handler.priority.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for handler in registry.handlers() {
+        let result = writeln!(
+            out,
+            "{:<name_width$}  {:>priority_width$}  {}",
+            handler.name,
+            handler.priority,
+            handler.matcher.uri_pattern().unwrap_or("-"),
+            name_width = name_width,
+            priority_width = priority_width
+        );
+        if result.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+pub fn export(args: &ExportArgs) -> bool {
+    let conn = match load_settings(&args.config.config)
+        .as_ref()
+        .and_then(connect)
+    {
+        Some(conn) => conn,
+        None => return false,
+    };
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    // Paged by id, so the whole table is never held in memory
+    let mut filter = queries::EventFilter {
+        after_id: None,
+        since: args.since,
+        handler: args.handler.clone(),
+        limit: None,
+    };
+    let mut exported = 0;
+    loop {
+        let remaining = args
+            .limit
+            .map_or(EXPORT_PAGE_SIZE, |limit| limit - exported);
+        if remaining <= 0 {
+            break;
+        }
+        filter.limit = Some(remaining.min(EXPORT_PAGE_SIZE));
+        let events = match queries::load_events(&conn, &filter) {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Failed to load events: {}", e);
+                return false;
+            }
+        };
+        let last_id = match events.last() {
+            Some(event) => event.id,
+            None => break,
+        };
+        for event in &events {
+            let result = serde_json::to_writer(&mut out, event)
+                .map_err(io::Error::from)
+                .and_then(|_| out.write_all(b"\n"));
+            if let Err(e) = result {
+                error!("Failed to write events: {}", e);
+                return false;
+            }
+        }
+        exported += events.len() as i64;
+        filter.after_id = Some(last_id);
+    }
+    if let Err(e) = out.flush() {
+        error!("Failed to write events: {}", e);
+        return false;
+    }
+    info!("Exported {} events", exported);
+    true
+}
+
+pub fn stats(args: &StatsArgs) -> bool {
+    let conn = match load_settings(&args.config.config)
+        .as_ref()
+        .and_then(connect)
+    {
+        Some(conn) => conn,
+        None => return false,
+    };
+    let stats = match queries::event_stats(&conn, args.since, args.top) {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Failed to compute statistics: {}", e);
+            return false;
+        }
+    };
+
+    let format_time = |time: Option<NaiveDateTime>| {
+        time.map_or_else(|| String::from("-"), |time| time.to_string())
+    };
+    let mut report = format!(
+        "Events:      {}\nSource IPs:  {}\nFirst event: {}\nLast event:  {}\n",
+        stats.total,
+        stats.distinct_ips,
+        format_time(stats.first),
+        format_time(stats.last)
+    );
+    let sections = [
+        ("Events per handler", &stats.handlers),
+        ("Top source IPs", &stats.top_ips),
+    ];
+    for (title, rows) in sections.iter() {
+        if rows.is_empty() {
+            continue;
+        }
+        let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+        report.push_str(&format!("\n{}:\n", title));
+        for (key, count) in rows.iter() {
+            report.push_str(&format!("  {:<width$}  {}\n", key, count, width = width));
+        }
+    }
+
+    io::stdout().write_all(report.as_bytes()).is_ok()
+}
@@ -0,0 +1,291 @@
+use crate::configuration::{ForwardingHeader, HttpConfig};
+use crate::handler::get_peer_address;
+use crate::listener::ConnectionMetadata;
+use actix_web::HttpRequest;
+use ipnetwork::IpNetwork;
+use log::trace;
+use std::net::{IpAddr, SocketAddr};
+
+// Resolution of the client address of a request. Forwarding headers are
+// only honoured when the peer is a trusted proxy, as anyone else can put
+// whatever they like in them.
+
+/// How the source address of a request was determined, recorded with every
+/// event as `ip_source`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpSource {
+    /// Address of the TCP peer
+    Peer,
+    /// Source address of the PROXY protocol header of the connection
+    ProxyProtocol,
+    Forwarded,
+    XForwardedFor,
+    XRealIp,
+}
+
+impl IpSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IpSource::Peer => "peer",
+            IpSource::ProxyProtocol => "proxy-protocol",
+            IpSource::Forwarded => "forwarded",
+            IpSource::XForwardedFor => "x-forwarded-for",
+            IpSource::XRealIp => "x-real-ip",
+        }
+    }
+}
+
+fn is_trusted(ip: IpAddr, trusted_proxies: &[IpNetwork]) -> bool {
+    trusted_proxies.iter().any(|network| network.contains(ip))
+}
+
+/// Parses a node of a forwarding header: an IP address, optionally with a
+/// port, IPv6 addresses possibly in brackets
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse::<IpAddr>().ok())
+        })
+}
+
+/// Values of every occurrence of a header, joined into a single list
+fn header_list(req: &HttpRequest, name: &str) -> Option<String> {
+    let values = req
+        .headers()
+        .get_all(name)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        .collect::<Vec<String>>();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(","))
+    }
+}
+
+/// `for` parameters of a `Forwarded` header (RFC 7239), in order. Nodes
+/// that aren't addresses (`unknown`, obfuscated identifiers) are `None`.
+fn forwarded_nodes(header: &str) -> Vec<Option<IpAddr>> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(parse_node(value.trim().trim_matches('"')))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+/// Walks a chain of forwarding hops from the right, skipping trusted
+/// proxies. Returns the first untrusted address, or the leftmost one if
+/// every hop is trusted. A node that isn't an address makes the whole
+/// chain unusable, as the hops before it can't be attributed.
+fn walk_chain(nodes: &[Option<IpAddr>], trusted_proxies: &[IpNetwork]) -> Option<IpAddr> {
+    let mut client = None;
+    for node in nodes.iter().rev() {
+        let ip = (*node)?;
+        client = Some(ip);
+        if !is_trusted(ip, trusted_proxies) {
+            break;
+        }
+    }
+    client
+}
+
+/// Determines the client address of a request, and how it was determined.
+///
+/// When the peer is one of the trusted proxies, the address is taken from
+/// the configured forwarding header, walking its chain of hops. On listeners
+/// using the PROXY protocol, the peer is the source address of its header.
+/// Requests received on a unix socket otherwise have no peer address; they
+/// are only trusted if `trust-unix-socket` says so.
+pub fn resolve(req: &HttpRequest, config: &HttpConfig) -> (Option<IpNetwork>, IpSource) {
+    let peer = get_peer_address(req);
+    let peer_source = match req.extensions().get::<ConnectionMetadata>() {
+        Some(metadata) if metadata.proxy_source.is_some() => IpSource::ProxyProtocol,
+        _ => IpSource::Peer,
+    };
+    let header = match config.forwarding_header {
+        Some(header) => header,
+        None => return (peer, peer_source),
+    };
+    let peer_trusted = match peer {
+        Some(peer) => is_trusted(peer.ip(), &config.trusted_proxies),
+        None => config.trust_unix_socket,
+    };
+    if !peer_trusted {
+        return (peer, peer_source);
+    }
+
+    let (name, source) = match header {
+        ForwardingHeader::Forwarded => ("Forwarded", IpSource::Forwarded),
+        ForwardingHeader::XForwardedFor => ("X-Forwarded-For", IpSource::XForwardedFor),
+        ForwardingHeader::XRealIp => ("X-Real-IP", IpSource::XRealIp),
+    };
+    let client = header_list(req, name).and_then(|value| {
+        let nodes = match header {
+            ForwardingHeader::Forwarded => forwarded_nodes(&value),
+            _ => value.split(',').map(parse_node).collect(),
+        };
+        walk_chain(&nodes, &config.trusted_proxies)
+    });
+    match client {
+        Some(ip) => (Some(IpNetwork::from(ip)), source),
+        None => {
+            trace!("No usable {} header from trusted peer {:?}", name, peer);
+            (peer, peer_source)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn config(header: ForwardingHeader) -> HttpConfig {
+        HttpConfig {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            forwarding_header: Some(header),
+            ..HttpConfig::default()
+        }
+    }
+
+    fn request(peer: Option<&str>, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut req = TestRequest::default();
+        if let Some(peer) = peer {
+            req = req.peer_addr(SocketAddr::new(ip(peer), 40000));
+        }
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.to_http_request()
+    }
+
+    fn resolved(req: &HttpRequest, config: &HttpConfig) -> (Option<IpAddr>, IpSource) {
+        let (ip, source) = resolve(req, config);
+        (ip.map(|ip| ip.ip()), source)
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node(" 192.0.2.1 "), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("192.0.2.1:8080"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]:443"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(
+            forwarded_nodes(r#"for=192.0.2.1;proto=http, For="[2001:db8::1]:80", for=_hidden"#),
+            vec![Some(ip("192.0.2.1")), Some(ip("2001:db8::1")), None]
+        );
+    }
+
+    #[test]
+    fn walks_chain_from_the_right() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let chain = |nodes: &[&str]| {
+            nodes
+                .iter()
+                .map(|node| parse_node(node))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            walk_chain(&chain(&["1.1.1.1", "2.2.2.2", "10.0.0.1"]), &trusted),
+            Some(ip("2.2.2.2"))
+        );
+        assert_eq!(
+            walk_chain(&chain(&["10.0.0.2", "10.0.0.1"]), &trusted),
+            Some(ip("10.0.0.2"))
+        );
+        assert_eq!(
+            walk_chain(&chain(&["1.1.1.1", "unknown", "10.0.0.1"]), &trusted),
+            None
+        );
+        // Spoofed hops left of the first untrusted one don't matter
+        assert_eq!(
+            walk_chain(&chain(&["unknown", "2.2.2.2"]), &trusted),
+            Some(ip("2.2.2.2"))
+        );
+        assert_eq!(walk_chain(&[], &trusted), None);
+    }
+
+    #[test]
+    fn reads_only_the_configured_header() {
+        let req = request(
+            Some("10.0.0.1"),
+            &[
+                ("Forwarded", "for=1.2.3.4"),
+                ("X-Forwarded-For", "1.1.1.1, 2.2.2.2"),
+                ("X-Real-IP", "3.3.3.3"),
+            ],
+        );
+        assert_eq!(
+            resolved(&req, &config(ForwardingHeader::XForwardedFor)),
+            (Some(ip("2.2.2.2")), IpSource::XForwardedFor)
+        );
+        assert_eq!(
+            resolved(&req, &config(ForwardingHeader::Forwarded)),
+            (Some(ip("1.2.3.4")), IpSource::Forwarded)
+        );
+        assert_eq!(
+            resolved(&req, &config(ForwardingHeader::XRealIp)),
+            (Some(ip("3.3.3.3")), IpSource::XRealIp)
+        );
+
+        // Missing header: the peer is the client
+        let req = request(Some("10.0.0.1"), &[("Forwarded", "for=1.2.3.4")]);
+        assert_eq!(
+            resolved(&req, &config(ForwardingHeader::XForwardedFor)),
+            (Some(ip("10.0.0.1")), IpSource::Peer)
+        );
+    }
+
+    #[test]
+    fn walks_x_real_ip_list() {
+        let req = request(Some("10.0.0.1"), &[("X-Real-IP", "6.6.6.6, 3.3.3.3")]);
+        assert_eq!(
+            resolved(&req, &config(ForwardingHeader::XRealIp)),
+            (Some(ip("3.3.3.3")), IpSource::XRealIp)
+        );
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let req = request(Some("192.0.2.1"), &[("X-Forwarded-For", "1.1.1.1")]);
+        assert_eq!(
+            resolved(&req, &config(ForwardingHeader::XForwardedFor)),
+            (Some(ip("192.0.2.1")), IpSource::Peer)
+        );
+
+        let req = request(Some("10.0.0.1"), &[("X-Forwarded-For", "1.1.1.1")]);
+        assert_eq!(
+            resolved(&req, &HttpConfig::default()),
+            (Some(ip("10.0.0.1")), IpSource::Peer)
+        );
+    }
+
+    #[test]
+    fn trusts_unix_sockets_only_when_told() {
+        let req = request(None, &[("X-Forwarded-For", "1.1.1.1")]);
+        let mut config = config(ForwardingHeader::XForwardedFor);
+        assert_eq!(resolved(&req, &config), (None, IpSource::Peer));
+        config.trust_unix_socket = true;
+        assert_eq!(
+            resolved(&req, &config),
+            (Some(ip("1.1.1.1")), IpSource::XForwardedFor)
+        );
+    }
+}
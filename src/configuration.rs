@@ -1,116 +1,695 @@
+use crate::handlers::declarative::HandlerDefinition;
+use crate::sink::{SinkDefinition, SinkKind};
+use crate::writer::OverflowPolicy;
+use actix_web::middleware::normalize::TrailingSlash;
 use config::Config;
+use ipnetwork::IpNetwork;
 use lazy_static::lazy_static;
-use log::error;
+use log::{error, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::sync::{RwLock, RwLockReadGuard};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 
 lazy_static! {
-    pub static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
-    pub static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings::default());
+    pub static ref SETTINGS: RwLock<Arc<Settings>> = RwLock::new(Arc::new(Settings::default()));
 }
 
-pub fn get_config_reader() -> RwLockReadGuard<'static, Config> {
-    CONFIG.read().unwrap_or_else(|e| {
-        error!("Failed to acquire read lock on config file: {}", e);
+pub fn get_settings_reader() -> RwLockReadGuard<'static, Arc<Settings>> {
+    SETTINGS.read().unwrap_or_else(|e| {
+        error!("Failed to acquire read lock on settings: {}", e);
         std::process::abort();
     })
 }
 
-pub fn get_settings_reader() -> RwLockReadGuard<'static, Settings> {
-    SETTINGS.read().unwrap_or_else(|e| {
-        error!("Failed to acquire read lock on settings: {}", e);
-        std::process::abort();
+/// Returns a snapshot of the current settings, which can be held across
+/// await points
+pub fn get_settings() -> Arc<Settings> {
+    get_settings_reader().clone()
+}
+
+/// Reads a configuration file, with overrides from `DEVIL_*` environment
+/// variables
+pub fn read_config(path: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    config
+        .merge(config::File::with_name(path))
+        .map_err(|e| format!("failed to load config file \"{}\": {}", path, e))?;
+    config
+        .merge(config::Environment::with_prefix("DEVIL"))
+        .map_err(|e| format!("failed to load environment variables: {}", e))?;
+    Ok(config)
+}
+
+/// Deserializes and validates the settings. Unknown keys are only warned
+/// about, as they are most likely typos of optional keys.
+pub fn parse_settings(config: &Config) -> Result<Settings, Vec<String>> {
+    let mut track = serde_path_to_error::Track::new();
+    let deserializer = serde_path_to_error::Deserializer::new(config.clone(), &mut track);
+    let settings: Settings = serde_ignored::deserialize(deserializer, |path| {
+        warn!("Unknown configuration key: {}", path)
     })
+    .map_err(|e| vec![format!("{}: {}", track.path(), e)])?;
+
+    let errors = settings.validate();
+    if errors.is_empty() {
+        Ok(settings)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Loads the configuration file and makes its settings active
+pub fn load_configuration(path: &str) -> Result<(), Vec<String>> {
+    let config = read_config(path).map_err(|e| vec![e])?;
+    set_settings(parse_settings(&config)?);
+    Ok(())
 }
 
-pub fn load_configuration() {
-    let settings = get_config_reader();
-    let parsed_config = Settings {
-        host: settings.get_str("http.host").unwrap_or_else(|_| {
-            error!("Failed to get HTTP host from config");
-            std::process::abort();
-        }),
-        port: settings.get_int("http.port").ok(),
-        workers: settings.get_int("http.workers").unwrap_or(2),
-        reporting_enabled: settings.get_bool("reporting.enabled").unwrap_or(false),
-        abuseipdb_key: settings.get_str("reporting.abuseipdb-key").ok(),
-        report_endpoint: settings
-            .get("report-endpoint")
-            .unwrap_or_else(|_| String::from("https://api.abuseipdb.com/api/v2/report")),
-        db_config: DatabaseConfig {
-            db_host: settings
-                .get_str("db.host")
-                .unwrap_or_else(|_| String::from("localhost")),
-            db_port: settings.get_int("db.port").unwrap_or(5432),
-            db_name: settings
-                .get_str("db.name")
-                .unwrap_or_else(|_| String::from("devil")),
-            db_user: settings
-                .get_str("db.user")
-                .unwrap_or_else(|_| String::from("devil")),
-            db_pass: settings
-                .get_str("db.pass")
-                .unwrap_or_else(|_| String::from("")),
-        },
-    };
-    drop(settings);
+/// Replaces the active settings. Requests already being handled keep the
+/// snapshot they started with.
+pub fn set_settings(parsed_config: Settings) {
     let mut settings_guard = SETTINGS.write().unwrap_or_else(|e| {
         error!("Failed to acquire write lock on settings: {}", e);
         std::process::abort();
     });
-    *settings_guard = parsed_config;
+    *settings_guard = Arc::new(parsed_config);
     drop(settings_guard);
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct Settings {
-    pub host: String,
-    pub port: Option<i64>,
-    pub workers: i64,
-    pub reporting_enabled: bool,
-    pub abuseipdb_key: Option<String>,
-    pub report_endpoint: String,
+    pub http: HttpConfig,
+    #[serde(default, rename = "db")]
     pub db_config: DatabaseConfig,
+    #[serde(default)]
+    pub reporting: ReportingConfig,
+    #[serde(default)]
+    pub handlers: Vec<HandlerDefinition>,
+    pub handlers_dir: Option<String>,
+    pub sinks: Option<Vec<SinkDefinition>>,
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub personas: Vec<PersonaDefinition>,
 }
 
 impl Settings {
     pub fn default() -> Self {
         Settings {
-            host: String::from("127.0.0.1"),
+            http: HttpConfig::default(),
+            db_config: Default::default(),
+            reporting: Default::default(),
+            handlers: Vec::new(),
+            handlers_dir: None,
+            sinks: None,
+            admin: None,
+            listeners: Vec::new(),
+            personas: Vec::new(),
+        }
+    }
+
+    /// Listeners to bind: those of `[[listeners]]`, or a single one on
+    /// `http.host` and `http.port`
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![ListenerConfig {
+            name: Some(String::from("http")),
+            host: self.http.host.clone(),
+            port: self.http.port,
+            proxy_protocol: self.http.proxy_protocol,
+            persona: None,
+            tls: None,
+        }]
+    }
+
+    /// Names of the handlers enabled on a listener, `None` if all of them
+    /// are
+    pub fn listener_handlers(&self, listener: &str) -> Option<&[String]> {
+        let persona = self
+            .listeners
+            .iter()
+            .find(|config| config.name() == listener)?
+            .persona
+            .as_ref()?;
+        self.personas
+            .iter()
+            .find(|definition| &definition.name == persona)
+            .map(|definition| definition.handlers.as_slice())
+    }
+
+    /// Whether events go to the database configured under `[db]`: without
+    /// `[[sinks]]`, or with a database sink among them
+    pub fn uses_database(&self) -> bool {
+        match &self.sinks {
+            Some(sinks) => sinks
+                .iter()
+                .any(|definition| matches!(definition.kind, SinkKind::Database)),
+            None => true,
+        }
+    }
+
+    /// Checks the constraints between keys, returning every violation
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.listeners.is_empty() {
+            let host_is_ip = self.http.host.parse::<IpAddr>().is_ok();
+            match self.http.port {
+                Some(_) if !host_is_ip => errors.push(String::from(
+                    "http.host must be an IP address when http.port is set; \
+                     a unix socket path is only valid without a port",
+                )),
+                None if host_is_ip => errors.push(String::from(
+                    "http.port is required when http.host is an IP address",
+                )),
+                _ => {}
+            }
+        } else if self.http.port.is_some() {
+            errors.push(String::from(
+                "http.port can't be used together with [[listeners]]",
+            ));
+        }
+        let mut listener_names = Vec::new();
+        for listener in &self.listeners {
+            let name = listener.name();
+            let host_is_ip = listener.host.parse::<IpAddr>().is_ok();
+            match listener.port {
+                Some(_) if !host_is_ip => errors.push(format!(
+                    "listener \"{}\": host must be an IP address when port is set; \
+                     a unix socket path is only valid without a port",
+                    name
+                )),
+                None if host_is_ip => errors.push(format!(
+                    "listener \"{}\": port is required when host is an IP address",
+                    name
+                )),
+                _ => {}
+            }
+            if let Some(persona) = &listener.persona {
+                if !self
+                    .personas
+                    .iter()
+                    .any(|definition| &definition.name == persona)
+                {
+                    errors.push(format!(
+                        "listener \"{}\": unknown persona \"{}\"",
+                        name, persona
+                    ));
+                }
+            }
+            if let Some(tls) = &listener.tls {
+                if tls.certificate.is_some() != tls.private_key.is_some() {
+                    errors.push(format!(
+                        "listener \"{}\": tls.certificate and tls.private-key must be set together",
+                        name
+                    ));
+                }
+            }
+            if listener_names.contains(&name) {
+                errors.push(format!("listener name \"{}\" is used more than once", name));
+            }
+            listener_names.push(name);
+        }
+        let trusts_proxies = !self.http.trusted_proxies.is_empty() || self.http.trust_unix_socket;
+        if trusts_proxies && self.http.forwarding_header.is_none() {
+            errors.push(String::from(
+                "http.forwarding-header is required to trust proxies",
+            ));
+        }
+        if self.http.workers == 0 {
+            errors.push(String::from("http.workers must be at least 1"));
+        }
+
+        if self.reporting.enabled
+            && self
+                .reporting
+                .abuseipdb_key
+                .as_deref()
+                .is_none_or(str::is_empty)
+        {
+            errors.push(String::from(
+                "reporting.abuseipdb-key is required when reporting is enabled",
+            ));
+        }
+        if self.reporting.enabled && !self.uses_database() {
+            errors.push(String::from(
+                "reporting needs the database, where reports are queued: \
+                 add a database sink to [[sinks]] or disable reporting",
+            ));
+        }
+        if self.reporting.max_attempts == 0 {
+            errors.push(String::from("reporting.max-attempts must be at least 1"));
+        }
+        if self.reporting.retry_delay == 0 {
+            errors.push(String::from("reporting.retry-delay must be at least 1"));
+        }
+        if self.reporting.bulk_window == Some(0) {
+            errors.push(String::from("reporting.bulk-window must be at least 1"));
+        }
+        if let Some(scoring) = &self.reporting.scoring {
+            if scoring.threshold == 0 {
+                errors.push(String::from(
+                    "reporting.scoring.threshold must be at least 1",
+                ));
+            }
+            if scoring.window == 0 {
+                errors.push(String::from("reporting.scoring.window must be at least 1"));
+            }
+        }
+
+        if let Some(admin) = &self.admin {
+            let loopback = admin.host == "localhost"
+                || admin
+                    .host
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| ip.is_loopback());
+            if !loopback && admin.token.as_deref().is_none_or(str::is_empty) {
+                errors.push(String::from(
+                    "admin.token is required unless admin.host is a loopback address",
+                ));
+            }
+        }
+
+        if self.db_config.queue_size == 0 {
+            errors.push(String::from("db.queue-size must be at least 1"));
+        }
+        if self.db_config.batch_size == 0 {
+            errors.push(String::from("db.batch-size must be at least 1"));
+        }
+
+        let database_sinks = self
+            .sinks
+            .iter()
+            .flatten()
+            .filter(|definition| matches!(definition.kind, SinkKind::Database))
+            .count();
+        if database_sinks > 1 {
+            errors.push(String::from("only one database sink can be configured"));
+        }
+        for definition in self.sinks.iter().flatten() {
+            if let SinkKind::Syslog(syslog) = &definition.kind {
+                if syslog.facility > 23 {
+                    errors.push(format!(
+                        "syslog sink {}: facility must be between 0 and 23",
+                        syslog.address
+                    ));
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HttpConfig {
+    /// IP address to listen on, or the path of a unix socket. Ignored when
+    /// `[[listeners]]` are defined, like `port` and `proxy-protocol`.
+    #[serde(default = "default_host")]
+    pub host: String,
+    pub port: Option<u16>,
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    #[serde(default)]
+    pub debug_matching: bool,
+    /// Seconds of inactivity after which a session is discarded
+    #[serde(default = "default_session_ttl")]
+    pub session_ttl: u64,
+    #[serde(default = "default_max_request_size")]
+    pub max_request_size: usize,
+    #[serde(default = "default_max_payload_size")]
+    pub max_payload_size: usize,
+    #[serde(default = "default_normalize_path")]
+    pub normalize_path: PathNormalization,
+    /// Expect a PROXY protocol header (v1 or v2) ahead of every connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Peers whose forwarding header is trusted to carry the client address
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// The one header set by the trusted proxies. The others are passed on
+    /// from the client as they are, so they are never read.
+    pub forwarding_header: Option<ForwardingHeader>,
+    /// Trust the forwarding header on unix sockets, which have no peer
+    /// address to check
+    #[serde(default)]
+    pub trust_unix_socket: bool,
+}
+
+fn default_host() -> String {
+    String::from("127.0.0.1")
+}
+
+fn default_workers() -> usize {
+    2
+}
+
+fn default_session_ttl() -> u64 {
+    3600
+}
+
+fn default_max_request_size() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_max_payload_size() -> usize {
+    64 * 1024
+}
+
+fn default_normalize_path() -> PathNormalization {
+    PathNormalization::Trim
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            host: default_host(),
             port: Some(8080),
-            workers: 2,
-            reporting_enabled: false,
+            workers: default_workers(),
+            debug_matching: false,
+            session_ttl: default_session_ttl(),
+            max_request_size: default_max_request_size(),
+            max_payload_size: default_max_payload_size(),
+            normalize_path: default_normalize_path(),
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            forwarding_header: None,
+            trust_unix_socket: false,
+        }
+    }
+}
+
+/// Address the HTTP server listens on. Listeners are only read at startup;
+/// their persona can be changed by a reload.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListenerConfig {
+    /// Name recorded with the events received on this listener, by default
+    /// its address
+    pub name: Option<String>,
+    /// IP address to listen on, or the path of a unix socket
+    pub host: String,
+    pub port: Option<u16>,
+    /// Expect a PROXY protocol header (v1 or v2) ahead of every connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Persona restricting the handlers run on this listener
+    pub persona: Option<String>,
+    /// Serve HTTPS instead of HTTP
+    pub tls: Option<TlsConfig>,
+}
+
+impl ListenerConfig {
+    pub fn name(&self) -> String {
+        match (&self.name, self.port) {
+            (Some(name), _) => name.clone(),
+            (None, Some(port)) => format!("{}:{}", self.host, port),
+            (None, None) => self.host.clone(),
+        }
+    }
+}
+
+/// Certificate of a TLS listener. Without `certificate` and `private-key`,
+/// a self-signed certificate is generated at startup from the other keys.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain
+    pub certificate: Option<String>,
+    /// PEM file with the private key (PKCS#8 or RSA)
+    pub private_key: Option<String>,
+    pub common_name: String,
+    /// Names in the generated certificate, `common-name` if empty
+    pub subject_alt_names: Vec<String>,
+    pub organization: Option<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            certificate: None,
+            private_key: None,
+            common_name: String::from("localhost"),
+            subject_alt_names: Vec::new(),
+            organization: None,
+        }
+    }
+}
+
+/// Named set of handlers, for listeners that should only look like a
+/// particular kind of server. The default handler answers everything else.
+#[derive(Debug, Deserialize)]
+pub struct PersonaDefinition {
+    pub name: String,
+    pub handlers: Vec<String>,
+}
+
+/// Listener for administrative requests, such as configuration reloads. It
+/// is only read at startup.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AdminConfig {
+    pub host: String,
+    pub port: u16,
+    /// Bearer token required on admin requests, if set. Only optional on a
+    /// loopback address.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ReportingConfig {
+    pub enabled: bool,
+    pub abuseipdb_key: Option<String>,
+    pub report_endpoint: String,
+    /// Add the JA3 and JA4 fingerprints of HTTPS clients to report comments
+    pub include_tls_fingerprints: bool,
+    /// Attempts after which a report failing with a network error or a
+    /// server error is given up
+    pub max_attempts: u32,
+    /// Seconds before the first retry of a report, doubled on every attempt
+    pub retry_delay: u64,
+    /// Seconds over which reports are gathered into a single bulk report,
+    /// instead of being sent one by one
+    pub bulk_window: Option<u64>,
+    pub bulk_report_endpoint: String,
+    /// Report addresses once they score enough, instead of on every report
+    pub scoring: Option<ScoringConfig>,
+    pub allowlist: AllowlistConfig,
+}
+
+impl Default for ReportingConfig {
+    fn default() -> Self {
+        ReportingConfig {
+            enabled: false,
             abuseipdb_key: None,
             report_endpoint: String::from("https://api.abuseipdb.com/api/v2/report"),
-            db_config: Default::default(),
+            include_tls_fingerprints: false,
+            max_attempts: 8,
+            retry_delay: 60,
+            bulk_window: None,
+            bulk_report_endpoint: String::from("https://api.abuseipdb.com/api/v2/bulk-report"),
+            scoring: None,
+            allowlist: AllowlistConfig::default(),
         }
     }
 }
 
-#[derive(Default, Debug)]
+/// Scoring policy for reports: every report adds the score of its handler
+/// to its address, which is reported once it reaches `threshold` within
+/// `window` seconds
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ScoringConfig {
+    pub threshold: u32,
+    pub window: u64,
+    /// Score of handlers missing from `scores`, `threshold` if unset, so
+    /// they are reported right away
+    pub default_score: Option<u32>,
+    /// Scores by handler name, or by `handler/subhandler`, which takes
+    /// precedence
+    pub scores: HashMap<String, u32>,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        ScoringConfig {
+            threshold: 10,
+            window: 3600,
+            default_score: None,
+            scores: HashMap::new(),
+        }
+    }
+}
+
+impl ScoringConfig {
+    pub fn score(&self, handler: &str, subhandler: Option<&str>) -> u32 {
+        subhandler
+            .and_then(|subhandler| self.scores.get(&format!("{}/{}", handler, subhandler)))
+            .or_else(|| self.scores.get(handler))
+            .copied()
+            .or(self.default_score)
+            .unwrap_or(self.threshold)
+    }
+}
+
+/// Addresses never reported. Their events are still recorded.
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct AllowlistConfig {
+    pub networks: Vec<IpNetwork>,
+    /// Don't report search engine crawlers, verified by forward-confirmed
+    /// reverse DNS against `crawler-domains`
+    pub verify_crawlers: bool,
+    /// Domains of the host names of crawlers, subdomains included
+    pub crawler_domains: Vec<String>,
+    /// DNS servers used to verify crawlers, instead of the system resolver
+    pub nameservers: Vec<SocketAddr>,
+    /// Seconds for which the verification of an address is cached
+    pub cache_ttl: u64,
+}
+
+impl Default for AllowlistConfig {
+    fn default() -> Self {
+        AllowlistConfig {
+            networks: Vec::new(),
+            verify_crawlers: true,
+            crawler_domains: [
+                "googlebot.com",
+                "search.msn.com",
+                "applebot.apple.com",
+                "crawl.yahoo.net",
+                "yandex.com",
+                "yandex.net",
+                "yandex.ru",
+                "crawl.baidu.com",
+                "crawl.baidu.jp",
+            ]
+            .iter()
+            .map(|domain| domain.to_string())
+            .collect(),
+            nameservers: Vec::new(),
+            cache_ttl: 86400,
+        }
+    }
+}
+
+/// How request paths are normalized before they are matched against
+/// handlers. The raw request target is recorded either way.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathNormalization {
+    Off,
+    Trim,
+    MergeOnly,
+    Always,
+}
+
+impl PathNormalization {
+    pub fn trailing_slash(&self) -> Option<TrailingSlash> {
+        match self {
+            PathNormalization::Off => None,
+            PathNormalization::Trim => Some(TrailingSlash::Trim),
+            PathNormalization::MergeOnly => Some(TrailingSlash::MergeOnly),
+            PathNormalization::Always => Some(TrailingSlash::Always),
+        }
+    }
+}
+
+/// Header carrying the client address through the trusted proxies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardingHeader {
+    Forwarded,
+    XForwardedFor,
+    XRealIp,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DatabaseBackend {
+    Postgres,
+    Sqlite,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
 pub struct DatabaseConfig {
+    pub backend: DatabaseBackend,
+    /// Run pending migrations at startup
+    pub migrate: bool,
+    /// Database file, for SQLite
+    #[serde(rename = "path")]
+    pub db_path: String,
+    #[serde(rename = "host")]
     pub db_host: String,
-    pub db_port: i64,
+    #[serde(rename = "port")]
+    pub db_port: u16,
+    #[serde(rename = "name")]
     pub db_name: String,
+    #[serde(rename = "user")]
     pub db_user: String,
+    #[serde(rename = "pass")]
     pub db_pass: String,
+    /// Maximum number of events waiting to be written
+    pub queue_size: usize,
+    /// Maximum number of events written with a single `INSERT`
+    pub batch_size: usize,
+    /// Milliseconds to wait for a full batch before writing a partial one
+    pub flush_interval: u64,
+    /// Directory where events are kept while the database is unreachable
+    pub spool_dir: String,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: DatabaseBackend::Postgres,
+            migrate: false,
+            db_path: String::from("devil.sqlite3"),
+            db_host: String::from("localhost"),
+            db_port: 5432,
+            db_name: String::from("devil"),
+            db_user: String::from("devil"),
+            db_pass: String::new(),
+            queue_size: 10000,
+            batch_size: 100,
+            flush_interval: 1000,
+            spool_dir: String::from("spool"),
+            overflow_policy: OverflowPolicy::DropOldest,
+        }
+    }
 }
 
 impl DatabaseConfig {
     pub fn construct_database_url(&self) -> String {
-        format!(
-            "postgres://{}:{}@{}:{}/{}",
-            self.db_user, self.db_pass, self.db_host, self.db_port, self.db_name
-        )
+        match self.backend {
+            DatabaseBackend::Postgres => format!(
+                "postgres://{}:{}@{}:{}/{}",
+                self.db_user, self.db_pass, self.db_host, self.db_port, self.db_name
+            ),
+            DatabaseBackend::Sqlite => self.db_path.clone(),
+        }
     }
 }
 
 impl Display for DatabaseConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "postgres://{}:[REDACTED]@{}:{}/{}",
-            self.db_user, self.db_host, self.db_port, self.db_name
-        )
+        match self.backend {
+            DatabaseBackend::Postgres => write!(
+                f,
+                "postgres://{}:[REDACTED]@{}:{}/{}",
+                self.db_user, self.db_host, self.db_port, self.db_name
+            ),
+            DatabaseBackend::Sqlite => write!(f, "sqlite://{}", self.db_path),
+        }
     }
 }
@@ -0,0 +1,238 @@
+use crate::client_ip::{self, IpSource};
+use crate::configuration::Settings;
+use crate::db::models::HandlerEvent;
+use crate::db::DbPool;
+use crate::listener::ConnectionMetadata;
+use crate::reporter::Report;
+use crate::session::{Session, SessionStore};
+use crate::tls::TlsMetadata;
+use crate::utils::request_target;
+use actix_web::error::PayloadError;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{web, HttpRequest};
+use futures_util::StreamExt;
+use ipnetwork::IpNetwork;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Information derived from the request, computed once by the dispatcher
+pub struct Enrichment {
+    pub src_ip: Option<IpNetwork>,
+    pub ip_source: IpSource,
+}
+
+impl Enrichment {
+    pub fn new(req: &HttpRequest, settings: &Settings) -> Self {
+        let (src_ip, ip_source) = client_ip::resolve(req, &settings.http);
+        Enrichment { src_ip, ip_source }
+    }
+}
+
+/// Request body read by the dispatcher. Larger bodies than
+/// `max-request-size` are not rejected: only that much of them is kept, the
+/// rest is counted and hashed.
+pub struct RequestBody {
+    /// Start of the body, seen by the matchers and handlers
+    pub bytes: Bytes,
+    /// Size of the whole body
+    pub size: usize,
+    /// SHA-256 of the whole body, in hexadecimal
+    pub sha256: String,
+}
+
+impl RequestBody {
+    pub async fn read(mut payload: web::Payload, limit: usize) -> Result<Self, PayloadError> {
+        let mut bytes = BytesMut::new();
+        let mut size = 0;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk?;
+            size += chunk.len();
+            hasher.update(&chunk);
+            let kept = chunk.len().min(limit - bytes.len());
+            bytes.extend_from_slice(&chunk[..kept]);
+        }
+        Ok(RequestBody {
+            bytes: bytes.freeze(),
+            size,
+            sha256: hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        })
+    }
+}
+
+/// Request target exactly as received, before path normalization. Stored
+/// in the request extensions by a middleware.
+pub struct RawUri(pub String);
+
+/// Headers in the order and case they were sent in, when the listener could
+/// record them. Stored in the request extensions by a middleware.
+pub struct RawHeaders(pub Vec<(String, String)>);
+
+/// Request data captured by the dispatcher for every request
+pub struct RequestMetadata {
+    pub method: String,
+    pub http_version: String,
+    /// Normalized URI, used for matching
+    pub uri: String,
+    pub raw_uri: Option<String>,
+    /// Query parameters in order of appearance, `None` if there is no query
+    /// string or it could not be decoded
+    pub query: Option<Vec<(String, String)>>,
+    /// Headers in the order and case they were sent in, if
+    /// `headers_in_order`. Otherwise, as on HTTP/2, they come from the map
+    /// actix parses them into: names are lowercased and only the values of
+    /// a repeated header keep their relative order.
+    pub headers: Vec<(String, String)>,
+    pub headers_in_order: bool,
+    pub peer_addr: Option<SocketAddr>,
+    /// Name of the listener the request was received on
+    pub listener: Option<String>,
+    /// Parameters of the TLS connection, on HTTPS listeners
+    pub tls: Option<TlsMetadata>,
+}
+
+impl RequestMetadata {
+    pub fn new(req: &HttpRequest) -> Self {
+        let raw_headers = req
+            .extensions()
+            .get::<RawHeaders>()
+            .map(|headers| headers.0.clone());
+        RequestMetadata {
+            method: req.method().to_string(),
+            http_version: format!("{:?}", req.version()),
+            uri: request_target(req.uri(), req.version()),
+            raw_uri: req
+                .extensions()
+                .get::<RawUri>()
+                .map(|raw_uri| raw_uri.0.clone()),
+            query: match req.query_string() {
+                "" => None,
+                query => web::Query::<Vec<(String, String)>>::from_query(query)
+                    .map(|query| query.into_inner())
+                    .ok(),
+            },
+            headers: match &raw_headers {
+                Some(headers) => headers.clone(),
+                None => req
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| {
+                        (
+                            name.to_string(),
+                            String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                        )
+                    })
+                    .collect(),
+            },
+            headers_in_order: raw_headers.is_some(),
+            peer_addr: req.peer_addr(),
+            listener: req
+                .extensions()
+                .get::<ConnectionMetadata>()
+                .map(|metadata| metadata.listener.clone()),
+            tls: req
+                .extensions()
+                .get::<ConnectionMetadata>()
+                .and_then(|metadata| metadata.tls.clone()),
+        }
+    }
+
+    /// Returns the first value of a header, looked up case-insensitively
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+}
+
+/// Everything a handler has access to while handling a request
+pub struct RequestContext {
+    pub handler_name: String,
+    pub req: HttpRequest,
+    pub metadata: RequestMetadata,
+    pub body: RequestBody,
+    /// Set when events go to the database. Events are recorded by the
+    /// dispatcher, so handlers only need it to read.
+    #[allow(dead_code)]
+    pub db_pool: Option<web::Data<DbPool>>,
+    pub settings: Arc<Settings>,
+    pub enrichment: Enrichment,
+    sessions: web::Data<SessionStore>,
+}
+
+impl RequestContext {
+    pub fn new(
+        handler_name: &str,
+        req: HttpRequest,
+        body: RequestBody,
+        db_pool: Option<web::Data<DbPool>>,
+        settings: Arc<Settings>,
+        sessions: web::Data<SessionStore>,
+    ) -> Self {
+        RequestContext {
+            handler_name: handler_name.to_string(),
+            enrichment: Enrichment::new(&req, &settings),
+            metadata: RequestMetadata::new(&req),
+            req,
+            body,
+            db_pool,
+            settings,
+            sessions,
+        }
+    }
+
+    /// Builds the event for this request, with all the request data
+    /// captured. The dispatcher adds the details returned by the handler.
+    pub fn new_event(&self) -> HandlerEvent {
+        HandlerEvent::new(&self.handler_name)
+            .set_host(self.metadata.header("Host"))
+            .set_uri(self.metadata.uri.clone())
+            .set_raw_uri(self.metadata.raw_uri.clone())
+            .set_x_forwarded_for(self.metadata.header("X-Forwarded-For"))
+            .set_src_ip(self.enrichment.src_ip)
+            .set_ip_source(self.enrichment.ip_source.as_str())
+            .set_peer_addr(self.metadata.peer_addr)
+            .set_listener(self.metadata.listener.clone())
+            .set_tls(self.metadata.tls.as_ref())
+            .set_user_agent(self.metadata.header("User-Agent"))
+            .set_method(self.metadata.method.clone())
+            .set_http_version(self.metadata.http_version.clone())
+            .set_headers(&self.metadata.headers, self.metadata.headers_in_order)
+            .set_query(self.metadata.query.as_deref())
+            .set_payload(&self.body, self.settings.http.max_payload_size)
+    }
+
+    /// Builds a report against the source address of this request, with the
+    /// request line as the comment, followed by the TLS fingerprints of the
+    /// client if `reporting.include-tls-fingerprints` is set
+    pub fn new_report(&self) -> Option<Report> {
+        let mut comment = format!("{} {}", self.metadata.method, self.metadata.uri);
+        if self.settings.reporting.include_tls_fingerprints {
+            if let Some(TlsMetadata {
+                ja3: Some(ja3),
+                ja4: Some(ja4),
+                ..
+            }) = &self.metadata.tls
+            {
+                comment.push_str(&format!(" (JA3 {}, JA4 {})", ja3, ja4));
+            }
+        }
+        self.enrichment
+            .src_ip
+            .map(|ip| Report::new(ip, &self.handler_name).set_comment_text(comment))
+    }
+
+    /// Runs `f` on the session of the source IP address. Returns `None` if
+    /// the source address is unknown.
+    pub fn with_session<T>(&self, f: impl FnOnce(&mut Session) -> T) -> Option<T> {
+        self.enrichment
+            .src_ip
+            .map(|ip| self.sessions.with_session(ip.ip(), f))
+    }
+}
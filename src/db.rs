@@ -1,35 +1,135 @@
-use crate::configuration::{get_config_reader, get_settings_reader};
+// Diesel 1.4's derives and `table!` expand to impls nested in constants,
+// which recent compilers lint against
+#![allow(non_local_definitions)]
+
+use crate::configuration::{get_settings_reader, DatabaseBackend, DatabaseConfig};
 use diesel::pg::PgConnection;
-use log::{debug, error};
-use r2d2::Pool;
+use diesel::sqlite::SqliteConnection;
+use diesel::Connection;
+use diesel_migrations::RunMigrationsError;
+use log::{debug, error, info, warn};
+use r2d2::{CustomizeConnection, Pool, PooledConnection};
 use r2d2_diesel::ConnectionManager;
+use std::time::Duration;
 
 pub mod models;
+pub mod queries;
+pub mod reports;
 pub mod schema;
+pub mod schema_sqlite;
+pub mod spool;
+
+mod postgres_migrations {
+    embed_migrations!("migrations");
+    pub use self::embedded_migrations::run;
+}
 
-embed_migrations!();
+mod sqlite_migrations {
+    embed_migrations!("migrations_sqlite");
+    pub use self::embedded_migrations::run;
+}
 
-pub type DbPool = Pool<ConnectionManager<PgConnection>>;
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Milliseconds a SQLite connection waits for a lock held by another one
+const SQLITE_BUSY_TIMEOUT: u32 = 5000;
 
-pub fn run_migrations(conn: &PgConnection) {
-    match diesel_migrations::run_pending_migrations(conn) {
-        Ok(_) => debug!("Migrations ran successfully"),
-        Err(e) => error!("Error running migrations: {}", e),
-    };
+/// Lets SQLite connections wait for each other, as events and reports are
+/// written from different threads
+#[derive(Debug)]
+struct SqliteBusyTimeout;
+
+impl CustomizeConnection<SqliteConnection, r2d2_diesel::Error> for SqliteBusyTimeout {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2_diesel::Error> {
+        conn.execute(&format!("PRAGMA busy_timeout = {}", SQLITE_BUSY_TIMEOUT))
+            .map(|_| ())
+            .map_err(r2d2_diesel::Error::QueryError)
+    }
 }
 
-pub fn establish_connection() -> DbPool {
-    let database_url = &get_settings_reader().db_config.construct_database_url();
+/// Connection pool for the configured backend (`db.backend`)
+#[derive(Clone)]
+pub enum DbPool {
+    Postgres(Pool<ConnectionManager<PgConnection>>),
+    Sqlite(Pool<ConnectionManager<SqliteConnection>>),
+}
+
+pub enum DbConnection {
+    Postgres(PooledConnection<ConnectionManager<PgConnection>>),
+    Sqlite(PooledConnection<ConnectionManager<SqliteConnection>>),
+}
+
+impl DbPool {
+    pub fn get(&self) -> Result<DbConnection, r2d2::Error> {
+        match self {
+            DbPool::Postgres(pool) => pool.get().map(DbConnection::Postgres),
+            DbPool::Sqlite(pool) => pool.get().map(DbConnection::Sqlite),
+        }
+    }
+}
+
+impl DbConnection {
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        match self {
+            DbConnection::Postgres(conn) => conn.transaction(f),
+            DbConnection::Sqlite(conn) => conn.transaction(f),
+        }
+    }
+}
+
+pub fn run_migrations(conn: &DbConnection) -> Result<(), RunMigrationsError> {
+    match conn {
+        DbConnection::Postgres(conn) => postgres_migrations::run(&**conn),
+        DbConnection::Sqlite(conn) => sqlite_migrations::run(&**conn),
+    }
+}
+
+pub fn run_migrations_if_enabled(conn: &DbConnection) {
+    if cfg!(test) || get_settings_reader().db_config.migrate {
+        match run_migrations(conn) {
+            Ok(_) => debug!("Migrations ran successfully"),
+            Err(e) => error!("Error running migrations: {}", e),
+        };
+    }
+}
+
+/// Creates a connection pool, without connecting to the database
+pub fn create_pool(db_config: &DatabaseConfig) -> DbPool {
+    let database_url = &db_config.construct_database_url();
     debug!("Connecting to database at {}", database_url);
 
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
-    let pool = r2d2::Pool::builder().build(manager).unwrap_or_else(|e| {
-        error!("Failed to create database pool: {}", e);
-        std::process::abort();
-    });
+    match db_config.backend {
+        DatabaseBackend::Postgres => DbPool::Postgres(
+            Pool::builder()
+                .connection_timeout(CONNECTION_TIMEOUT)
+                .build_unchecked(ConnectionManager::new(database_url)),
+        ),
+        DatabaseBackend::Sqlite => DbPool::Sqlite(
+            Pool::builder()
+                .connection_timeout(CONNECTION_TIMEOUT)
+                .connection_customizer(Box::new(SqliteBusyTimeout))
+                .build_unchecked(ConnectionManager::new(database_url)),
+        ),
+    }
+}
+
+/// Creates the connection pool. The database doesn't need to be reachable:
+/// until it is, events are spooled to disk by the writer.
+pub fn establish_connection() -> DbPool {
+    let pool = create_pool(&get_settings_reader().db_config);
 
-    if cfg!(test) || get_config_reader().get_bool("db.migrate").unwrap_or(false) {
-        run_migrations(&pool.get().expect("Failed to acquire database connection"));
+    match pool.get() {
+        Ok(conn) => {
+            run_migrations_if_enabled(&conn);
+            info!("Connected to database");
+        }
+        Err(e) => warn!(
+            "Failed to connect to database, starting in spool-only mode: {}",
+            e
+        ),
     }
 
     pool
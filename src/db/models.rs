@@ -1,11 +1,15 @@
 use super::schema::handler_events;
 use super::schema::handler_events::dsl::handler_events as handler_events_dsl;
+use super::schema_sqlite::handler_events as sqlite_handler_events;
+use super::DbConnection;
+use crate::context::RequestBody;
+use crate::tls::TlsMetadata;
 use diesel::prelude::*;
 use ipnetwork::IpNetwork;
-use log::error;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
-#[derive(Debug, Deserialize, Serialize, Queryable, Insertable)]
+#[derive(Debug, Clone, Deserialize, Serialize, Queryable, Insertable)]
 #[table_name = "handler_events"]
 pub struct HandlerEvent {
     pub handler: String,
@@ -17,6 +21,29 @@ pub struct HandlerEvent {
     pub user_agent: Option<String>,
     pub handler_data: Option<String>,
     pub x_forwarded_for: Option<String>,
+    pub method: Option<String>,
+    pub http_version: Option<String>,
+    pub headers: Option<serde_json::Value>,
+    /// Whether `headers` are in the order and case they were sent in
+    pub headers_in_order: Option<bool>,
+    pub query: Option<serde_json::Value>,
+    /// Stored part of the body, base64 encoded when serialized
+    #[serde(with = "base64_bytes")]
+    pub payload_raw: Option<Vec<u8>>,
+    pub payload_size: Option<i64>,
+    pub payload_truncated: Option<bool>,
+    pub payload_sha256: Option<String>,
+    pub raw_uri: Option<String>,
+    pub ip_source: Option<String>,
+    /// Peer address of the connection: the proxy, or the source given by
+    /// its PROXY protocol header
+    pub peer_addr: Option<String>,
+    pub listener: Option<String>,
+    pub tls_sni: Option<String>,
+    pub tls_alpn: Option<String>,
+    pub tls_version: Option<String>,
+    pub tls_ja3: Option<String>,
+    pub tls_ja4: Option<String>,
 }
 
 impl HandlerEvent {
@@ -31,6 +58,24 @@ impl HandlerEvent {
             user_agent: None,
             handler_data: None,
             x_forwarded_for: None,
+            method: None,
+            http_version: None,
+            headers: None,
+            headers_in_order: None,
+            query: None,
+            payload_raw: None,
+            payload_size: None,
+            payload_truncated: None,
+            payload_sha256: None,
+            raw_uri: None,
+            ip_source: None,
+            peer_addr: None,
+            listener: None,
+            tls_sni: None,
+            tls_alpn: None,
+            tls_version: None,
+            tls_ja3: None,
+            tls_ja4: None,
         }
     }
 
@@ -49,13 +94,58 @@ impl HandlerEvent {
         self
     }
 
+    pub fn set_raw_uri(mut self, raw_uri: Option<String>) -> Self {
+        self.raw_uri = raw_uri;
+        self
+    }
+
     pub fn set_src_ip(mut self, src_ip: Option<IpNetwork>) -> Self {
         self.src_ip = src_ip;
         self
     }
 
-    pub fn set_payload(mut self, payload: Option<String>) -> Self {
-        self.payload = payload;
+    pub fn set_ip_source(mut self, ip_source: &str) -> Self {
+        self.ip_source = Some(ip_source.to_string());
+        self
+    }
+
+    pub fn set_peer_addr(mut self, peer_addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = peer_addr.map(|addr| addr.to_string());
+        self
+    }
+
+    pub fn set_listener(mut self, listener: Option<String>) -> Self {
+        self.listener = listener;
+        self
+    }
+
+    /// Sets what was negotiated in the TLS handshake, if the request was
+    /// made over TLS
+    pub fn set_tls(mut self, tls: Option<&TlsMetadata>) -> Self {
+        if let Some(tls) = tls {
+            self.tls_sni = tls.sni.clone();
+            self.tls_alpn = tls.alpn.clone();
+            self.tls_version = tls.version.clone();
+            self.tls_ja3 = tls.ja3.clone();
+            self.tls_ja4 = tls.ja4.clone();
+        }
+        self
+    }
+
+    /// Stores the request body: at most `max_size` bytes of it verbatim, a
+    /// lossy UTF-8 preview of the stored part, and the size and SHA-256 of
+    /// the whole body
+    pub fn set_payload(mut self, body: &RequestBody, max_size: usize) -> Self {
+        if body.size == 0 {
+            return self;
+        }
+        let stored = &body.bytes[..body.bytes.len().min(max_size)];
+        // PostgreSQL text can't hold NUL characters
+        self.payload = Some(String::from_utf8_lossy(stored).replace('\0', "\u{FFFD}"));
+        self.payload_raw = Some(stored.to_vec());
+        self.payload_size = Some(body.size as i64);
+        self.payload_truncated = Some(stored.len() < body.size);
+        self.payload_sha256 = Some(body.sha256.clone());
         self
     }
 
@@ -73,12 +163,179 @@ impl HandlerEvent {
         self
     }
 
-    pub fn insert(handler_event: Self, conn: &PgConnection) {
-        if let Err(e) = diesel::insert_into(handler_events_dsl)
-            .values(handler_event)
-            .execute(conn)
-        {
-            error!("Error inserting new event: {}", e);
+    pub fn set_method(mut self, method: String) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    pub fn set_http_version(mut self, http_version: String) -> Self {
+        self.http_version = Some(http_version);
+        self
+    }
+
+    /// Sets the headers, stored as an array of `[name, value]` pairs, and
+    /// whether they are in the order and case they were sent in
+    pub fn set_headers(mut self, headers: &[(String, String)], in_order: bool) -> Self {
+        self.headers = serde_json::to_value(headers).ok();
+        self.headers_in_order = Some(in_order);
+        self
+    }
+
+    /// Sets the query parameters, stored as an array of `[name, value]` pairs
+    pub fn set_query(mut self, query: Option<&[(String, String)]>) -> Self {
+        self.query = query.and_then(|query| serde_json::to_value(query).ok());
+        self
+    }
+
+    /// Inserts the events with a single multi-row `INSERT` (one `INSERT`
+    /// per event in a transaction on SQLite)
+    pub fn insert_batch(handler_events: &[Self], conn: &DbConnection) -> QueryResult<usize> {
+        match conn {
+            DbConnection::Postgres(conn) => diesel::insert_into(handler_events_dsl)
+                .values(handler_events)
+                .execute(&**conn),
+            DbConnection::Sqlite(conn) => diesel::insert_into(sqlite_handler_events::table)
+                .values(
+                    handler_events
+                        .iter()
+                        .map(SqliteHandlerEvent::from)
+                        .collect::<Vec<_>>(),
+                )
+                .execute(&**conn),
+        }
+    }
+}
+
+/// Row of the SQLite table, which has no IP address or JSON types: those
+/// are stored as text
+#[derive(Insertable)]
+#[table_name = "sqlite_handler_events"]
+struct SqliteHandlerEvent<'a> {
+    handler: &'a str,
+    subhandler: Option<&'a str>,
+    host: Option<&'a str>,
+    uri: Option<&'a str>,
+    raw_uri: Option<&'a str>,
+    src_ip: Option<String>,
+    ip_source: Option<&'a str>,
+    peer_addr: Option<&'a str>,
+    listener: Option<&'a str>,
+    tls_sni: Option<&'a str>,
+    tls_alpn: Option<&'a str>,
+    tls_version: Option<&'a str>,
+    tls_ja3: Option<&'a str>,
+    tls_ja4: Option<&'a str>,
+    payload: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    handler_data: Option<&'a str>,
+    x_forwarded_for: Option<&'a str>,
+    method: Option<&'a str>,
+    http_version: Option<&'a str>,
+    headers: Option<String>,
+    headers_in_order: Option<bool>,
+    query: Option<String>,
+    payload_raw: Option<&'a [u8]>,
+    payload_size: Option<i64>,
+    payload_truncated: Option<bool>,
+    payload_sha256: Option<&'a str>,
+}
+
+impl<'a> From<&'a HandlerEvent> for SqliteHandlerEvent<'a> {
+    fn from(event: &'a HandlerEvent) -> Self {
+        SqliteHandlerEvent {
+            handler: &event.handler,
+            subhandler: event.subhandler.as_deref(),
+            host: event.host.as_deref(),
+            uri: event.uri.as_deref(),
+            raw_uri: event.raw_uri.as_deref(),
+            src_ip: event.src_ip.map(|ip| ip.ip().to_string()),
+            ip_source: event.ip_source.as_deref(),
+            peer_addr: event.peer_addr.as_deref(),
+            listener: event.listener.as_deref(),
+            tls_sni: event.tls_sni.as_deref(),
+            tls_alpn: event.tls_alpn.as_deref(),
+            tls_version: event.tls_version.as_deref(),
+            tls_ja3: event.tls_ja3.as_deref(),
+            tls_ja4: event.tls_ja4.as_deref(),
+            payload: event.payload.as_deref(),
+            user_agent: event.user_agent.as_deref(),
+            handler_data: event.handler_data.as_deref(),
+            x_forwarded_for: event.x_forwarded_for.as_deref(),
+            method: event.method.as_deref(),
+            http_version: event.http_version.as_deref(),
+            headers: event.headers.as_ref().map(|headers| headers.to_string()),
+            headers_in_order: event.headers_in_order,
+            query: event.query.as_ref().map(|query| query.to_string()),
+            payload_raw: event.payload_raw.as_deref(),
+            payload_size: event.payload_size,
+            payload_truncated: event.payload_truncated,
+            payload_sha256: event.payload_sha256.as_deref(),
+        }
+    }
+}
+
+/// Serializes optional bytes as a base64 string rather than an array of
+/// numbers
+mod base64_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&base64::encode(bytes)),
+            None => serializer.serialize_none(),
         }
     }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|encoded| base64::decode(encoded).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(bytes: &'static [u8], size: usize) -> RequestBody {
+        RequestBody {
+            bytes: bytes.into(),
+            size,
+            sha256: String::from("00"),
+        }
+    }
+
+    #[test]
+    fn truncates_payload() {
+        let event = HandlerEvent::new("test").set_payload(&body(b"abcdef", 6), 4);
+        assert_eq!(event.payload.as_deref(), Some("abcd"));
+        assert_eq!(event.payload_raw.as_deref(), Some(&b"abcd"[..]));
+        assert_eq!(event.payload_size, Some(6));
+        assert_eq!(event.payload_truncated, Some(true));
+
+        // Cut short by the dispatcher already
+        let event = HandlerEvent::new("test").set_payload(&body(b"ab", 1000), 4);
+        assert_eq!(event.payload_size, Some(1000));
+        assert_eq!(event.payload_truncated, Some(true));
+
+        let event = HandlerEvent::new("test").set_payload(&body(b"", 0), 4);
+        assert_eq!(event.payload_raw, None);
+    }
+
+    #[test]
+    fn serializes_raw_payload_as_base64() {
+        let event = HandlerEvent::new("test").set_payload(&body(b"\x00\xffabc", 5), 16);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["payload_raw"], "AP9hYmM=");
+        let event: HandlerEvent = serde_json::from_value(json).unwrap();
+        assert_eq!(event.payload_raw.as_deref(), Some(&b"\x00\xffabc"[..]));
+
+        let json = serde_json::to_value(HandlerEvent::new("test")).unwrap();
+        assert!(json["payload_raw"].is_null());
+    }
 }
@@ -0,0 +1,454 @@
+use super::models::HandlerEvent;
+use super::schema::handler_events as pg;
+use super::schema_sqlite::handler_events as sqlite;
+use super::DbConnection;
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::dsl::sql;
+use diesel::expression::functions::aggregate_ordering::{max, min};
+use diesel::expression::SqlLiteral;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+
+// Read queries over the stored events, for the command line tools. Both
+// backends return the same results; on SQLite, the IP addresses and JSON
+// columns stored as text are parsed back.
+
+/// Conditions on the events to read; `None` means no condition
+#[derive(Default)]
+pub struct EventFilter {
+    /// Only events with a greater id, to page through them
+    pub after_id: Option<i32>,
+    pub since: Option<NaiveDateTime>,
+    pub handler: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct StoredEvent {
+    pub id: i32,
+    pub timestamp: NaiveDateTime,
+    #[serde(flatten)]
+    pub event: HandlerEvent,
+}
+
+#[derive(Serialize)]
+pub struct EventStats {
+    pub total: i64,
+    pub distinct_ips: i64,
+    pub first: Option<NaiveDateTime>,
+    pub last: Option<NaiveDateTime>,
+    /// Number of events per handler, most frequent first
+    pub handlers: Vec<(String, i64)>,
+    /// Source addresses with the most events, most frequent first
+    pub top_ips: Vec<(String, i64)>,
+}
+
+type SqliteRow = (
+    i32,
+    NaiveDateTime,
+    (
+        String,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+    ),
+    (
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<bool>,
+        Option<String>,
+        Option<Vec<u8>>,
+        Option<i64>,
+        Option<bool>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+    ),
+);
+
+fn from_sqlite_row(row: SqliteRow) -> StoredEvent {
+    let (id, timestamp, first, second) = row;
+    let (
+        handler,
+        subhandler,
+        host,
+        uri,
+        src_ip,
+        payload,
+        user_agent,
+        handler_data,
+        x_forwarded_for,
+    ) = first;
+    let (
+        method,
+        http_version,
+        headers,
+        headers_in_order,
+        query,
+        payload_raw,
+        payload_size,
+        payload_truncated,
+        payload_sha256,
+        raw_uri,
+        ip_source,
+        peer_addr,
+        listener,
+        tls_sni,
+        tls_alpn,
+        tls_version,
+        tls_ja3,
+        tls_ja4,
+    ) = second;
+    StoredEvent {
+        id,
+        timestamp,
+        event: HandlerEvent {
+            handler,
+            subhandler,
+            host,
+            uri,
+            src_ip: src_ip.and_then(|ip| ip.parse::<IpNetwork>().ok()),
+            payload,
+            user_agent,
+            handler_data,
+            x_forwarded_for,
+            method,
+            http_version,
+            headers: headers.and_then(|headers| serde_json::from_str(&headers).ok()),
+            headers_in_order,
+            query: query.and_then(|query| serde_json::from_str(&query).ok()),
+            payload_raw,
+            payload_size,
+            payload_truncated,
+            payload_sha256,
+            raw_uri,
+            ip_source,
+            peer_addr,
+            listener,
+            tls_sni,
+            tls_alpn,
+            tls_version,
+            tls_ja3,
+            tls_ja4,
+        },
+    }
+}
+
+/// Returns the events matching `filter`, oldest first
+pub fn load_events(conn: &DbConnection, filter: &EventFilter) -> QueryResult<Vec<StoredEvent>> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            let mut query = pg::table
+                .select((
+                    pg::id,
+                    pg::timestamp,
+                    (
+                        pg::handler,
+                        pg::subhandler,
+                        pg::host,
+                        pg::uri,
+                        pg::src_ip,
+                        pg::payload,
+                        pg::user_agent,
+                        pg::handler_data,
+                        pg::x_forwarded_for,
+                        pg::method,
+                        pg::http_version,
+                        pg::headers,
+                        pg::headers_in_order,
+                        pg::query,
+                        pg::payload_raw,
+                        pg::payload_size,
+                        pg::payload_truncated,
+                        pg::payload_sha256,
+                        pg::raw_uri,
+                        pg::ip_source,
+                        pg::peer_addr,
+                        pg::listener,
+                        pg::tls_sni,
+                        pg::tls_alpn,
+                        pg::tls_version,
+                        pg::tls_ja3,
+                        pg::tls_ja4,
+                    ),
+                ))
+                .order(pg::id)
+                .into_boxed();
+            if let Some(after_id) = filter.after_id {
+                query = query.filter(pg::id.gt(after_id));
+            }
+            if let Some(since) = filter.since {
+                query = query.filter(pg::timestamp.ge(since));
+            }
+            if let Some(handler) = &filter.handler {
+                query = query.filter(pg::handler.eq(handler));
+            }
+            if let Some(limit) = filter.limit {
+                query = query.limit(limit);
+            }
+            Ok(query
+                .load::<(i32, NaiveDateTime, HandlerEvent)>(&**conn)?
+                .into_iter()
+                .map(|(id, timestamp, event)| StoredEvent {
+                    id,
+                    timestamp,
+                    event,
+                })
+                .collect())
+        }
+        DbConnection::Sqlite(conn) => {
+            let mut query = sqlite::table
+                .select((
+                    sqlite::id,
+                    sqlite::timestamp,
+                    (
+                        sqlite::handler,
+                        sqlite::subhandler,
+                        sqlite::host,
+                        sqlite::uri,
+                        sqlite::src_ip,
+                        sqlite::payload,
+                        sqlite::user_agent,
+                        sqlite::handler_data,
+                        sqlite::x_forwarded_for,
+                    ),
+                    (
+                        sqlite::method,
+                        sqlite::http_version,
+                        sqlite::headers,
+                        sqlite::headers_in_order,
+                        sqlite::query,
+                        sqlite::payload_raw,
+                        sqlite::payload_size,
+                        sqlite::payload_truncated,
+                        sqlite::payload_sha256,
+                        sqlite::raw_uri,
+                        sqlite::ip_source,
+                        sqlite::peer_addr,
+                        sqlite::listener,
+                        sqlite::tls_sni,
+                        sqlite::tls_alpn,
+                        sqlite::tls_version,
+                        sqlite::tls_ja3,
+                        sqlite::tls_ja4,
+                    ),
+                ))
+                .order(sqlite::id)
+                .into_boxed();
+            if let Some(after_id) = filter.after_id {
+                query = query.filter(sqlite::id.gt(after_id));
+            }
+            if let Some(since) = filter.since {
+                query = query.filter(sqlite::timestamp.ge(since));
+            }
+            if let Some(handler) = &filter.handler {
+                query = query.filter(sqlite::handler.eq(handler));
+            }
+            if let Some(limit) = filter.limit {
+                query = query.limit(limit);
+            }
+            Ok(query
+                .load::<SqliteRow>(&**conn)?
+                .into_iter()
+                .map(from_sqlite_row)
+                .collect())
+        }
+    }
+}
+
+/// `COUNT(*)` for grouped queries, where diesel doesn't accept `count_star`
+/// next to the grouping column
+fn count() -> SqlLiteral<BigInt> {
+    sql::<BigInt>("COUNT(*)")
+}
+
+/// Summarizes the events recorded since `since`, or all of them
+pub fn event_stats(
+    conn: &DbConnection,
+    since: Option<NaiveDateTime>,
+    top: i64,
+) -> QueryResult<EventStats> {
+    // No event predates the epoch, so it stands in for a missing `since`
+    let since = since.unwrap_or_else(|| NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0));
+    match conn {
+        DbConnection::Postgres(conn) => {
+            let events = pg::table.filter(pg::timestamp.ge(since));
+            let total = events.count().get_result::<i64>(&**conn)?;
+            let first = events
+                .select(min(pg::timestamp))
+                .first::<Option<NaiveDateTime>>(&**conn)?;
+            let last = events
+                .select(max(pg::timestamp))
+                .first::<Option<NaiveDateTime>>(&**conn)?;
+            let distinct_ips = events
+                .select(sql::<BigInt>("COUNT(DISTINCT src_ip)"))
+                .first::<i64>(&**conn)?;
+            let handlers = events
+                .group_by(pg::handler)
+                .select((pg::handler, count()))
+                .order((count().desc(), pg::handler))
+                .load::<(String, i64)>(&**conn)?;
+            let top_ips = events
+                .filter(pg::src_ip.is_not_null())
+                .group_by(pg::src_ip)
+                .select((pg::src_ip, count()))
+                .order(count().desc())
+                .limit(top)
+                .load::<(Option<IpNetwork>, i64)>(&**conn)?
+                .into_iter()
+                .filter_map(|(ip, count)| ip.map(|ip| (ip.ip().to_string(), count)))
+                .collect();
+            Ok(EventStats {
+                total,
+                distinct_ips,
+                first,
+                last,
+                handlers,
+                top_ips,
+            })
+        }
+        DbConnection::Sqlite(conn) => {
+            let events = sqlite::table.filter(sqlite::timestamp.ge(since));
+            let total = events.count().get_result::<i64>(&**conn)?;
+            let first = events
+                .select(min(sqlite::timestamp))
+                .first::<Option<NaiveDateTime>>(&**conn)?;
+            let last = events
+                .select(max(sqlite::timestamp))
+                .first::<Option<NaiveDateTime>>(&**conn)?;
+            let distinct_ips = events
+                .select(sql::<BigInt>("COUNT(DISTINCT src_ip)"))
+                .first::<i64>(&**conn)?;
+            let handlers = events
+                .group_by(sqlite::handler)
+                .select((sqlite::handler, count()))
+                .order((count().desc(), sqlite::handler))
+                .load::<(String, i64)>(&**conn)?;
+            let top_ips = events
+                .filter(sqlite::src_ip.is_not_null())
+                .group_by(sqlite::src_ip)
+                .select((sqlite::src_ip, count()))
+                .order(count().desc())
+                .limit(top)
+                .load::<(Option<String>, i64)>(&**conn)?
+                .into_iter()
+                .filter_map(|(ip, count)| ip.map(|ip| (ip, count)))
+                .collect();
+            Ok(EventStats {
+                total,
+                distinct_ips,
+                first,
+                last,
+                handlers,
+                top_ips,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{DatabaseBackend, DatabaseConfig};
+    use crate::db::{create_pool, run_migrations};
+    use std::fs;
+
+    fn event(handler: &str, src_ip: Option<&str>) -> HandlerEvent {
+        HandlerEvent::new(handler).set_src_ip(src_ip.map(|ip| ip.parse().unwrap()))
+    }
+
+    fn handlers(events: &[StoredEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|stored| stored.event.handler.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn filters_and_summarizes_events_on_sqlite() {
+        let dir = std::env::temp_dir().join(format!("devil-queries-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let pool = create_pool(&DatabaseConfig {
+            backend: DatabaseBackend::Sqlite,
+            db_path: dir.join("test.sqlite3").to_string_lossy().into_owned(),
+            ..DatabaseConfig::default()
+        });
+        let conn = pool.get().unwrap();
+        run_migrations(&conn).unwrap();
+
+        let headers = [("Host".to_string(), "example.com".to_string())];
+        let events = [
+            event("a", Some("192.0.2.1")),
+            event("b", Some("192.0.2.1")).set_headers(&headers, true),
+            event("a", Some("2001:db8::1")),
+            event("c", None),
+            event("a", Some("192.0.2.1")),
+        ];
+        HandlerEvent::insert_batch(&events, &conn).unwrap();
+        let old = NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0);
+        if let DbConnection::Sqlite(conn) = &conn {
+            diesel::sql_query(
+                "UPDATE handler_events SET timestamp = '2020-01-01 00:00:00' WHERE id = 1",
+            )
+            .execute(&**conn)
+            .unwrap();
+        }
+
+        let all = load_events(&conn, &EventFilter::default()).unwrap();
+        assert_eq!(handlers(&all), ["a", "b", "a", "c", "a"]);
+        assert_eq!(all[0].timestamp, old);
+        // Addresses and JSON columns stored as text are parsed back
+        assert_eq!(all[2].event.src_ip, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(all[1].event.headers, events[1].headers);
+        assert_eq!(all[1].event.headers_in_order, Some(true));
+
+        let page = EventFilter {
+            after_id: Some(all[1].id),
+            limit: Some(2),
+            ..EventFilter::default()
+        };
+        let page = load_events(&conn, &page).unwrap();
+        assert_eq!(page.iter().map(|e| e.id).collect::<Vec<_>>(), [3, 4]);
+        let recent_a = EventFilter {
+            since: Some(old + chrono::Duration::days(1)),
+            handler: Some("a".to_string()),
+            ..EventFilter::default()
+        };
+        let recent_a = load_events(&conn, &recent_a).unwrap();
+        assert_eq!(recent_a.iter().map(|e| e.id).collect::<Vec<_>>(), [3, 5]);
+
+        let stats = event_stats(&conn, None, 1).unwrap();
+        assert_eq!(stats.total, 5);
+        assert_eq!(stats.distinct_ips, 2);
+        assert_eq!(stats.first, Some(old));
+        assert_eq!(stats.last, Some(all[4].timestamp));
+        let expected = [("a", 3), ("b", 1), ("c", 1)];
+        assert_eq!(
+            stats.handlers,
+            expected.map(|(handler, count)| (handler.to_string(), count))
+        );
+        assert_eq!(stats.top_ips, [("192.0.2.1".to_string(), 3)]);
+
+        let stats = event_stats(&conn, Some(old + chrono::Duration::days(1)), 5).unwrap();
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.handlers[0], ("a".to_string(), 2));
+        assert_eq!(stats.top_ips.len(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,154 @@
+use super::schema::reports;
+use super::DbConnection;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+
+// Reports to AbuseIPDB, stored in the `reports` table from the moment they
+// are queued. Both the pending reports and the deduplication window survive
+// restarts this way.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportStatus {
+    /// Waiting to be sent
+    Queued,
+    Sent,
+    /// Rejected by AbuseIPDB
+    Failed,
+}
+
+impl ReportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportStatus::Queued => "queued",
+            ReportStatus::Sent => "sent",
+            ReportStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "reports"]
+pub struct NewReport {
+    pub created_at: NaiveDateTime,
+    pub ip: String,
+    /// AbuseIPDB category IDs, comma-separated
+    pub categories: String,
+    pub comment: Option<String>,
+    pub status: &'static str,
+    pub attempts: i32,
+    pub updated_at: NaiveDateTime,
+    pub retry_at: Option<NaiveDateTime>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Queryable)]
+pub struct StoredReport {
+    pub id: i32,
+    pub created_at: NaiveDateTime,
+    pub ip: String,
+    pub categories: String,
+    pub comment: Option<String>,
+    pub status: String,
+    pub attempts: i32,
+    /// Time of the last attempt to send the report
+    pub updated_at: NaiveDateTime,
+    pub http_status: Option<i32>,
+    /// Body of the last AbuseIPDB response, or the error of the last attempt
+    pub response: Option<String>,
+    /// Time before which a queued report is not sent
+    pub retry_at: Option<NaiveDateTime>,
+}
+
+pub fn insert(conn: &DbConnection, report: &NewReport) -> QueryResult<usize> {
+    let query = diesel::insert_into(reports::table).values(report);
+    match conn {
+        DbConnection::Postgres(conn) => query.execute(&**conn),
+        DbConnection::Sqlite(conn) => query.execute(&**conn),
+    }
+}
+
+/// Whether a report against `ip` made at or after `since` was sent, or
+/// failed
+pub fn is_reported(conn: &DbConnection, ip: &str, since: NaiveDateTime) -> QueryResult<bool> {
+    let query = reports::table
+        .select(reports::id)
+        .filter(reports::ip.eq(ip))
+        .filter(reports::created_at.ge(since))
+        .filter(reports::status.ne(ReportStatus::Queued.as_str()));
+    let found = match conn {
+        DbConnection::Postgres(conn) => query.first::<i32>(&**conn).optional(),
+        DbConnection::Sqlite(conn) => query.first::<i32>(&**conn).optional(),
+    };
+    found.map(|id| id.is_some())
+}
+
+/// Categories of the reports against `ip` waiting to be sent
+pub fn queued_categories(conn: &DbConnection, ip: &str) -> QueryResult<Vec<String>> {
+    let query = reports::table
+        .select(reports::categories)
+        .filter(reports::ip.eq(ip))
+        .filter(reports::status.eq(ReportStatus::Queued.as_str()));
+    match conn {
+        DbConnection::Postgres(conn) => query.load(&**conn),
+        DbConnection::Sqlite(conn) => query.load(&**conn),
+    }
+}
+
+/// Returns at most `limit` queued reports which are due, oldest first
+pub fn load_queued(conn: &DbConnection, limit: i64) -> QueryResult<Vec<StoredReport>> {
+    let query = reports::table
+        .filter(reports::status.eq(ReportStatus::Queued.as_str()))
+        .filter(
+            reports::retry_at
+                .is_null()
+                .or(reports::retry_at.le(Utc::now().naive_utc())),
+        )
+        .order(reports::id)
+        .limit(limit);
+    match conn {
+        DbConnection::Postgres(conn) => query.load(&**conn),
+        DbConnection::Sqlite(conn) => query.load(&**conn),
+    }
+}
+
+/// Records an attempt at sending reports, and the status it leaves them
+/// in. `retry_at` is when to try again reports left queued.
+pub fn record_attempts(
+    conn: &DbConnection,
+    ids: &[i32],
+    status: ReportStatus,
+    http_status: Option<i32>,
+    response: Option<&str>,
+    retry_at: Option<NaiveDateTime>,
+) -> QueryResult<usize> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    let query = diesel::update(reports::table.filter(reports::id.eq_any(ids))).set((
+        reports::status.eq(status.as_str()),
+        reports::attempts.eq(reports::attempts + 1),
+        reports::updated_at.eq(Utc::now().naive_utc()),
+        reports::http_status.eq(http_status),
+        reports::response.eq(response),
+        reports::retry_at.eq(retry_at),
+    ));
+    match conn {
+        DbConnection::Postgres(conn) => query.execute(&**conn),
+        DbConnection::Sqlite(conn) => query.execute(&**conn),
+    }
+}
+
+/// Holds back every queued report until `until`, so a pause asked for by
+/// AbuseIPDB outlasts restarts
+pub fn postpone_queued(conn: &DbConnection, until: NaiveDateTime) -> QueryResult<usize> {
+    let query = diesel::update(
+        reports::table
+            .filter(reports::status.eq(ReportStatus::Queued.as_str()))
+            .filter(reports::retry_at.is_null().or(reports::retry_at.lt(until))),
+    )
+    .set(reports::retry_at.eq(until));
+    match conn {
+        DbConnection::Postgres(conn) => query.execute(&**conn),
+        DbConnection::Sqlite(conn) => query.execute(&**conn),
+    }
+}
@@ -11,5 +11,40 @@ table! {
         user_agent -> Nullable<Text>,
         handler_data -> Nullable<Text>,
         x_forwarded_for -> Nullable<Text>,
+        method -> Nullable<Text>,
+        http_version -> Nullable<Text>,
+        headers -> Nullable<Jsonb>,
+        query -> Nullable<Jsonb>,
+        payload_raw -> Nullable<Binary>,
+        payload_size -> Nullable<BigInt>,
+        payload_truncated -> Nullable<Bool>,
+        payload_sha256 -> Nullable<Text>,
+        raw_uri -> Nullable<Text>,
+        ip_source -> Nullable<Text>,
+        listener -> Nullable<Text>,
+        tls_sni -> Nullable<Text>,
+        tls_alpn -> Nullable<Text>,
+        tls_version -> Nullable<Text>,
+        tls_ja3 -> Nullable<Text>,
+        tls_ja4 -> Nullable<Text>,
+        peer_addr -> Nullable<Text>,
+        headers_in_order -> Nullable<Bool>,
+    }
+}
+
+// Same columns on both backends, so there is no SQLite variant
+table! {
+    reports (id) {
+        id -> Integer,
+        created_at -> Timestamp,
+        ip -> Text,
+        categories -> Text,
+        comment -> Nullable<Text>,
+        status -> Text,
+        attempts -> Integer,
+        updated_at -> Timestamp,
+        http_status -> Nullable<Integer>,
+        response -> Nullable<Text>,
+        retry_at -> Nullable<Timestamp>,
     }
 }
@@ -0,0 +1,33 @@
+table! {
+    handler_events (id) {
+        id -> Integer,
+        timestamp -> Timestamp,
+        handler -> Text,
+        subhandler -> Nullable<Text>,
+        host -> Nullable<Text>,
+        uri -> Nullable<Text>,
+        raw_uri -> Nullable<Text>,
+        src_ip -> Nullable<Text>,
+        payload -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        handler_data -> Nullable<Text>,
+        x_forwarded_for -> Nullable<Text>,
+        method -> Nullable<Text>,
+        http_version -> Nullable<Text>,
+        headers -> Nullable<Text>,
+        query -> Nullable<Text>,
+        payload_raw -> Nullable<Binary>,
+        payload_size -> Nullable<BigInt>,
+        payload_truncated -> Nullable<Bool>,
+        payload_sha256 -> Nullable<Text>,
+        ip_source -> Nullable<Text>,
+        listener -> Nullable<Text>,
+        tls_sni -> Nullable<Text>,
+        tls_alpn -> Nullable<Text>,
+        tls_version -> Nullable<Text>,
+        tls_ja3 -> Nullable<Text>,
+        tls_ja4 -> Nullable<Text>,
+        peer_addr -> Nullable<Text>,
+        headers_in_order -> Nullable<Bool>,
+    }
+}
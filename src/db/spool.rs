@@ -0,0 +1,335 @@
+use super::models::HandlerEvent;
+use super::DbConnection;
+use diesel::{QueryResult, RunQueryDsl};
+use log::{error, info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+const SPOOL_FILE: &str = "events.jsonl";
+/// Byte offset in the spool file up to which events have been replayed
+const PROGRESS_FILE: &str = "events.jsonl.offset";
+/// Events the database refused while it was up, kept for inspection
+const REJECTED_FILE: &str = "events.jsonl.rejected";
+
+/// Append-only file holding events that could not be written to the
+/// database, one JSON object per line, in the order they were received
+pub struct Spool {
+    path: PathBuf,
+    progress_path: PathBuf,
+    rejected_path: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: &str) -> Self {
+        let dir = PathBuf::from(dir);
+        Spool {
+            path: dir.join(SPOOL_FILE),
+            progress_path: dir.join(PROGRESS_FILE),
+            rejected_path: dir.join(REJECTED_FILE),
+        }
+    }
+
+    /// Whether there are spooled events waiting to be replayed
+    pub fn is_pending(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn append(&self, events: &[HandlerEvent]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if !self.path.exists() {
+            // Left behind by a replay which removed the spool file only: it
+            // would skip the start of the new one
+            remove_if_exists(&self.progress_path)?;
+        }
+        append_events(&self.path, events)
+    }
+
+    /// Writes the spooled events to the database, committing every
+    /// `batch_size` events and recording how far it got, and removes the
+    /// spool file once all of them are written. Returns `false` if the
+    /// database failed, in which case the replay resumes from the last
+    /// committed batch on the next attempt.
+    pub fn replay(&self, conn: &DbConnection, batch_size: usize) -> bool {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return true,
+            Err(e) => {
+                error!("Failed to open spool file {:?}: {}", self.path, e);
+                return false;
+            }
+        };
+        let mut offset = self.progress();
+        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+            error!("Failed to read spool file {:?}: {}", self.path, e);
+            return false;
+        }
+        let mut reader = BufReader::new(file);
+
+        let mut written = 0;
+        let mut rejected = 0;
+        loop {
+            let (batch, end) = match read_batch(&mut reader, offset, batch_size) {
+                Ok(read) => read,
+                Err(e) => {
+                    error!("Failed to read spool file {:?}: {}", self.path, e);
+                    return false;
+                }
+            };
+            if end == offset {
+                break;
+            }
+            match insert(&batch, conn) {
+                Ok((count, refused)) => {
+                    written += count;
+                    rejected += refused.len();
+                    self.reject(&refused);
+                }
+                Err(e) => {
+                    warn!("Failed to replay spooled events: {}", e);
+                    return false;
+                }
+            }
+            if let Err(e) = self.save_progress(end) {
+                // The batch may be written again by the next replay
+                error!("Failed to record spool replay progress: {}", e);
+                return false;
+            }
+            offset = end;
+        }
+
+        info!("Replayed {} spooled events", written);
+        if rejected > 0 {
+            warn!(
+                "{} spooled events were refused by the database, moved to {:?}",
+                rejected, self.rejected_path
+            );
+        }
+        // The progress file stays if the spool file can't be removed, so
+        // the events aren't replayed twice
+        match fs::remove_file(&self.path) {
+            Ok(()) => {
+                if let Err(e) = remove_if_exists(&self.progress_path) {
+                    error!("Failed to remove {:?}: {}", self.progress_path, e);
+                }
+            }
+            Err(e) => error!("Failed to remove spool file {:?}: {}", self.path, e),
+        }
+        true
+    }
+
+    /// Offset up to which the spool file has been replayed
+    fn progress(&self) -> u64 {
+        fs::read_to_string(&self.progress_path)
+            .ok()
+            .and_then(|offset| offset.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    fn save_progress(&self, offset: u64) -> io::Result<()> {
+        // Renamed into place, so a crash can't leave a partial offset
+        let tmp = self.progress_path.with_extension("offset.tmp");
+        fs::write(&tmp, offset.to_string())?;
+        fs::rename(&tmp, &self.progress_path)
+    }
+
+    fn reject(&self, events: &[&HandlerEvent]) {
+        if events.is_empty() {
+            return;
+        }
+        let events = events
+            .iter()
+            .map(|&event| event.clone())
+            .collect::<Vec<_>>();
+        if let Err(e) = append_events(&self.rejected_path, &events) {
+            error!(
+                "Failed to keep {} rejected events in {:?}, dropping them: {}",
+                events.len(),
+                self.rejected_path,
+                e
+            );
+        }
+    }
+}
+
+/// Reads up to `batch_size` events starting at `offset`. Returns them with
+/// the offset following the last line read, which is `offset` at the end of
+/// the file.
+fn read_batch<R: BufRead>(
+    reader: &mut R,
+    offset: u64,
+    batch_size: usize,
+) -> io::Result<(Vec<HandlerEvent>, u64)> {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut end = offset;
+    let mut line = Vec::new();
+    while batch.len() < batch_size.max(1) {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        end += read as u64;
+        match serde_json::from_slice::<HandlerEvent>(&line) {
+            Ok(event) => batch.push(event),
+            // A line cut short by a crash must not block the rest
+            Err(e) => warn!("Skipping unreadable spooled event: {}", e),
+        }
+    }
+    Ok((batch, end))
+}
+
+/// Inserts a batch in one transaction. If the database refuses it while it
+/// is still up, the events are inserted one by one instead, and the ones it
+/// refuses are returned to be set aside.
+fn insert<'a>(
+    batch: &'a [HandlerEvent],
+    conn: &DbConnection,
+) -> QueryResult<(usize, Vec<&'a HandlerEvent>)> {
+    if batch.is_empty() {
+        return Ok((0, Vec::new()));
+    }
+    let error = match conn.transaction(|| HandlerEvent::insert_batch(batch, conn)) {
+        Ok(count) => return Ok((count, Vec::new())),
+        Err(e) => e,
+    };
+    if !responds(conn) {
+        return Err(error);
+    }
+
+    conn.transaction(|| {
+        let mut count = 0;
+        let mut refused = Vec::new();
+        for event in batch {
+            // Nested transactions are savepoints, so a refused event
+            // doesn't abort the others
+            match conn.transaction(|| HandlerEvent::insert_batch(std::slice::from_ref(event), conn))
+            {
+                Ok(inserted) => count += inserted,
+                Err(e) => {
+                    warn!("Database refused spooled event: {}", e);
+                    refused.push(event);
+                }
+            }
+        }
+        // The database may have gone down halfway
+        if !refused.is_empty() && !responds(conn) {
+            return Err(error);
+        }
+        Ok((count, refused))
+    })
+}
+
+/// Whether the database answers queries
+fn responds(conn: &DbConnection) -> bool {
+    let query = diesel::sql_query("SELECT 1");
+    match conn {
+        DbConnection::Postgres(conn) => query.execute(&**conn).is_ok(),
+        DbConnection::Sqlite(conn) => query.execute(&**conn).is_ok(),
+    }
+}
+
+fn append_events(path: &PathBuf, events: &[HandlerEvent]) -> io::Result<()> {
+    let mut lines = Vec::new();
+    for event in events {
+        serde_json::to_writer(&mut lines, event)?;
+        lines.push(b'\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&lines)?;
+    file.sync_data()
+}
+
+fn remove_if_exists(path: &PathBuf) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{DatabaseBackend, DatabaseConfig};
+    use crate::db::queries::{load_events, EventFilter};
+    use crate::db::{create_pool, run_migrations};
+    use std::io::Cursor;
+
+    fn line(handler: &str) -> String {
+        format!(
+            "{}\n",
+            serde_json::to_string(&HandlerEvent::new(handler)).unwrap()
+        )
+    }
+
+    #[test]
+    fn reads_batches_with_offsets() {
+        let spool = format!("{}{}{{\"cut short\n{}", line("a"), line("b"), line("c"));
+        let mut reader = Cursor::new(spool.as_bytes());
+
+        let (batch, end) = read_batch(&mut reader, 0, 2).unwrap();
+        let handlers = batch.iter().map(|event| event.handler.as_str());
+        assert_eq!(handlers.collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(end as usize, line("a").len() + line("b").len());
+
+        // The unreadable line is skipped
+        let (batch, next) = read_batch(&mut reader, end, 2).unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].handler, "c");
+        assert_eq!(next as usize, spool.len());
+
+        assert_eq!(read_batch(&mut reader, next, 2).unwrap().1, next);
+    }
+
+    fn handlers(conn: &DbConnection) -> Vec<String> {
+        let events = load_events(conn, &EventFilter::default()).unwrap();
+        events
+            .into_iter()
+            .map(|stored| stored.event.handler)
+            .collect()
+    }
+
+    #[test]
+    fn replays_in_batches_and_sets_refused_events_aside() {
+        let dir = std::env::temp_dir().join(format!("devil-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let pool = create_pool(&DatabaseConfig {
+            backend: DatabaseBackend::Sqlite,
+            db_path: dir.join("test.sqlite3").to_string_lossy().into_owned(),
+            ..DatabaseConfig::default()
+        });
+        let conn = pool.get().unwrap();
+        run_migrations(&conn).unwrap();
+        if let DbConnection::Sqlite(conn) = &conn {
+            diesel::sql_query(
+                "CREATE TRIGGER refuse BEFORE INSERT ON handler_events \
+                 WHEN NEW.handler = 'bad' BEGIN SELECT RAISE(ABORT, 'refused'); END",
+            )
+            .execute(&**conn)
+            .unwrap();
+        }
+
+        let spool = Spool::new(dir.to_str().unwrap());
+        let events = ["a", "b", "bad", "c", "d"].map(HandlerEvent::new);
+        spool.append(&events).unwrap();
+        assert!(spool.replay(&conn, 2));
+        assert!(!spool.is_pending());
+        assert!(!spool.progress_path.exists());
+
+        let stored = handlers(&conn);
+        assert_eq!(stored, ["a", "b", "c", "d"]);
+        let rejected = fs::read_to_string(&spool.rejected_path).unwrap();
+        assert_eq!(rejected, line("bad"));
+
+        // Resumes after the last recorded batch
+        spool.append(&events[..2]).unwrap();
+        spool.save_progress(line("a").len() as u64).unwrap();
+        assert!(spool.replay(&conn, 2));
+        assert_eq!(handlers(&conn).len(), 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
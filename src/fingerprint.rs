@@ -0,0 +1,451 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+// TLS client fingerprints, computed from the ClientHello of a connection:
+// JA3 (https://github.com/salesforce/ja3) and JA4
+// (https://github.com/FoxIO-LLC/ja4). They describe the TLS stack of the
+// client rather than what it claims to be, so scanners sharing a tool share
+// fingerprints whatever their User-Agent.
+
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXTENSION_EC_POINT_FORMATS: u16 = 0x000b;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXTENSION_ALPN: u16 = 0x0010;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// Cursor over a TLS structure. Every read fails on truncated input.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.data.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|bytes| (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+    }
+
+    /// Vector with a one byte length prefix
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let length = self.u8()? as usize;
+        self.take(length).map(Reader::new)
+    }
+
+    /// Vector with a two byte length prefix
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let length = self.u16()? as usize;
+        self.take(length).map(Reader::new)
+    }
+
+    fn u16_list(mut self) -> Option<Vec<u16>> {
+        let mut values = Vec::with_capacity(self.data.len() / 2);
+        while !self.is_empty() {
+            values.push(self.u16()?);
+        }
+        Some(values)
+    }
+}
+
+/// GREASE values (RFC 8701) are random, so fingerprints leave them out
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Fields of a ClientHello that go into the fingerprints, in the order the
+/// client sent them, GREASE values included
+#[derive(Default)]
+pub struct ClientHello {
+    version: u16,
+    ciphers: Vec<u16>,
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    supported_versions: Vec<u16>,
+    /// First protocol offered with ALPN
+    alpn: Option<Vec<u8>>,
+}
+
+impl ClientHello {
+    /// Parses the ClientHello from the TLS records received at the start of
+    /// a connection. The message may span several records.
+    pub fn parse(records: &[u8]) -> Option<Self> {
+        let mut records = Reader::new(records);
+        let mut message = Vec::new();
+        loop {
+            // Handshake records only
+            if records.u8()? != 22 {
+                return None;
+            }
+            records.u16()?;
+            let fragment = records.vec16()?;
+            message.extend_from_slice(fragment.data);
+            if message.len() >= 4 {
+                let length = Reader::new(&message[1..4]).u24()?;
+                if message.len() >= 4 + length {
+                    break;
+                }
+            }
+        }
+
+        let mut message = Reader::new(&message);
+        if message.u8()? != 1 {
+            return None;
+        }
+        let length = message.u24()?;
+        let mut body = Reader::new(message.take(length)?);
+
+        let mut hello = ClientHello {
+            version: body.u16()?,
+            ..Default::default()
+        };
+        // Random and session ID
+        body.take(32)?;
+        body.vec8()?;
+        hello.ciphers = body.vec16()?.u16_list()?;
+        // Compression methods
+        body.vec8()?;
+        if body.is_empty() {
+            return Some(hello);
+        }
+
+        let mut extensions = body.vec16()?;
+        while !extensions.is_empty() {
+            let extension = extensions.u16()?;
+            let mut data = extensions.vec16()?;
+            hello.extensions.push(extension);
+            match extension {
+                EXTENSION_SUPPORTED_GROUPS => hello.groups = data.vec16()?.u16_list()?,
+                EXTENSION_EC_POINT_FORMATS => hello.point_formats = data.vec8()?.data.to_vec(),
+                EXTENSION_SIGNATURE_ALGORITHMS => {
+                    hello.signature_algorithms = data.vec16()?.u16_list()?
+                }
+                EXTENSION_ALPN => {
+                    hello.alpn = data.vec16()?.vec8().map(|protocol| protocol.data.to_vec())
+                }
+                EXTENSION_SUPPORTED_VERSIONS => {
+                    hello.supported_versions = data.vec8()?.u16_list()?
+                }
+                _ => {}
+            }
+        }
+        Some(hello)
+    }
+
+    /// JA3 fingerprint: MD5 of the version, ciphers, extensions, groups and
+    /// point formats, in decimal and in the order they were sent
+    pub fn ja3(&self) -> String {
+        let list = |values: &[u16]| {
+            values
+                .iter()
+                .filter(|value| !is_grease(**value))
+                .map(|value| value.to_string())
+                .collect::<Vec<String>>()
+                .join("-")
+        };
+        let point_formats = self
+            .point_formats
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<String>>()
+            .join("-");
+        let ja3 = format!(
+            "{},{},{},{},{}",
+            self.version,
+            list(&self.ciphers),
+            list(&self.extensions),
+            list(&self.groups),
+            point_formats
+        );
+        hex(&Md5::digest(ja3.as_bytes()))
+    }
+
+    /// JA4 fingerprint (TLS over TCP), such as
+    /// `t13d1516h2_8daaf6152771_e5627efa2ab1`: a readable summary of the
+    /// ClientHello, then truncated SHA-256 of the sorted ciphers, and of the
+    /// sorted extensions with the signature algorithms
+    pub fn ja4(&self) -> String {
+        let ciphers = self
+            .ciphers
+            .iter()
+            .copied()
+            .filter(|value| !is_grease(*value))
+            .collect::<Vec<u16>>();
+        let extensions = self
+            .extensions
+            .iter()
+            .copied()
+            .filter(|value| !is_grease(*value))
+            .collect::<Vec<u16>>();
+
+        let version = self
+            .supported_versions
+            .iter()
+            .copied()
+            .filter(|value| !is_grease(*value))
+            .max()
+            .unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+        let sni = if extensions.contains(&EXTENSION_SERVER_NAME) {
+            'd'
+        } else {
+            'i'
+        };
+        let alpn = match self.alpn.as_deref() {
+            Some(protocol) if !protocol.is_empty() => {
+                let (first, last) = (protocol[0], protocol[protocol.len() - 1]);
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                } else {
+                    format!("{:x}{:x}", first >> 4, last & 0x0f)
+                }
+            }
+            _ => String::from("00"),
+        };
+        let summary = format!(
+            "t{}{}{:02}{:02}{}",
+            version,
+            sni,
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn
+        );
+
+        let mut sorted_ciphers = ciphers;
+        sorted_ciphers.sort_unstable();
+        let mut sorted_extensions = extensions
+            .into_iter()
+            .filter(|value| *value != EXTENSION_SERVER_NAME && *value != EXTENSION_ALPN)
+            .collect::<Vec<u16>>();
+        sorted_extensions.sort_unstable();
+        let signature_algorithms = self
+            .signature_algorithms
+            .iter()
+            .copied()
+            .filter(|value| !is_grease(*value))
+            .collect::<Vec<u16>>();
+
+        let ciphers_hash = truncated_hash(&hex_list(&sorted_ciphers));
+        let extensions_hash = if sorted_extensions.is_empty() {
+            truncated_hash("")
+        } else if signature_algorithms.is_empty() {
+            truncated_hash(&hex_list(&sorted_extensions))
+        } else {
+            truncated_hash(&format!(
+                "{}_{}",
+                hex_list(&sorted_extensions),
+                hex_list(&signature_algorithms)
+            ))
+        };
+        format!("{}_{}_{}", summary, ciphers_hash, extensions_hash)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_list(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{:04x}", value))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// First 12 hex digits of the SHA-256 of `list`, or zeros if it is empty
+fn truncated_hash(list: &str) -> String {
+    if list.is_empty() {
+        return String::from("000000000000");
+    }
+    hex(&Sha256::digest(list.as_bytes()))[..12].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut vec = (data.len() as u16).to_be_bytes().to_vec();
+        vec.extend_from_slice(data);
+        vec
+    }
+
+    fn vec8(data: &[u8]) -> Vec<u8> {
+        let mut vec = vec![data.len() as u8];
+        vec.extend_from_slice(data);
+        vec
+    }
+
+    /// ClientHello handshake message
+    fn client_hello(version: u16, ciphers: &[u16], extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = version.to_be_bytes().to_vec();
+        body.extend_from_slice(&[0; 32]);
+        body.extend(vec8(&[1; 32]));
+        body.extend(vec16(&u16s(ciphers)));
+        body.extend(vec8(&[0]));
+        let extensions = extensions
+            .iter()
+            .flat_map(|(extension, data)| {
+                let mut bytes = extension.to_be_bytes().to_vec();
+                bytes.extend(vec16(data));
+                bytes
+            })
+            .collect::<Vec<u8>>();
+        body.extend(vec16(&extensions));
+
+        let mut message = vec![1];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend(body);
+        message
+    }
+
+    /// Handshake records carrying `message`, cut into fragments of at most
+    /// `fragment_size` bytes
+    fn records(message: &[u8], fragment_size: usize) -> Vec<u8> {
+        message
+            .chunks(fragment_size)
+            .flat_map(|fragment| {
+                let mut record = vec![22, 3, 1];
+                record.extend(vec16(fragment));
+                record
+            })
+            .collect()
+    }
+
+    /// The JA3 example of https://github.com/salesforce/ja3
+    fn ja3_example() -> Vec<u8> {
+        client_hello(
+            0x0301,
+            &[47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4],
+            &[
+                (0, vec16(&[0, 0, 4, b't', b'e', b's', b't'])),
+                (10, vec16(&u16s(&[23, 24, 25]))),
+                (11, vec8(&[0])),
+            ],
+        )
+    }
+
+    /// The ClientHello of the JA4 example of
+    /// https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md,
+    /// with GREASE values added
+    fn ja4_example() -> Vec<u8> {
+        let ciphers = [
+            0x0a0a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+            0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+        ];
+        let signature_algorithms = [
+            0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+        ];
+        let empty = Vec::new;
+        client_hello(
+            0x0303,
+            &ciphers,
+            &[
+                (0x2a2a, empty()),
+                (0x0000, vec16(&[0, 0, 4, b't', b'e', b's', b't'])),
+                (0x0017, empty()),
+                (0xff01, vec8(&[])),
+                (0x000a, vec16(&u16s(&[0x3a3a, 0x001d, 0x0017, 0x0018]))),
+                (0x000b, vec8(&[0])),
+                (0x0023, empty()),
+                (0x0010, vec16(&vec8(b"h2"))),
+                (0x0005, vec![1, 0, 0, 0, 0]),
+                (0x000d, vec16(&u16s(&signature_algorithms))),
+                (0x0012, empty()),
+                (0x0033, vec16(&[])),
+                (0x002d, vec8(&[1])),
+                (0x002b, vec8(&u16s(&[0x5a5a, 0x0304, 0x0303]))),
+                (0x001b, vec![2, 0, 2]),
+                (0x4469, vec16(&vec8(b"h2"))),
+                (0x0015, vec![0; 8]),
+                (0x1a1a, vec![0]),
+            ],
+        )
+    }
+
+    #[test]
+    fn computes_published_ja3() {
+        let hello = ClientHello::parse(&records(&ja3_example(), 16384)).unwrap();
+        assert_eq!(hello.ja3(), "ada70206e40642a3e4461f35503241d5");
+    }
+
+    #[test]
+    fn computes_published_ja4() {
+        let hello = ClientHello::parse(&records(&ja4_example(), 16384)).unwrap();
+        assert_eq!(hello.ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+        assert_eq!(hello.alpn.as_deref(), Some(&b"h2"[..]));
+    }
+
+    #[test]
+    fn reassembles_fragmented_hello() {
+        let whole = ClientHello::parse(&records(&ja4_example(), 16384)).unwrap();
+        let fragmented = ClientHello::parse(&records(&ja4_example(), 7)).unwrap();
+        assert_eq!(fragmented.ja3(), whole.ja3());
+        assert_eq!(fragmented.ja4(), whole.ja4());
+    }
+
+    #[test]
+    fn rejects_invalid_hello() {
+        let records = records(&ja4_example(), 16384);
+        for length in [0, 5, 9, 50, records.len() - 1] {
+            assert!(ClientHello::parse(&records[..length]).is_none());
+        }
+        // Not a handshake record
+        let mut alert = records.clone();
+        alert[0] = 21;
+        assert!(ClientHello::parse(&alert).is_none());
+        // ServerHello
+        let mut server_hello = records;
+        server_hello[5] = 2;
+        assert!(ClientHello::parse(&server_hello).is_none());
+    }
+
+    #[test]
+    fn recognizes_grease() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x1301));
+    }
+}
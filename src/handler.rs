@@ -1,36 +1,60 @@
-use crate::configuration::get_settings_reader;
-use crate::db::models;
+use crate::configuration::{self, get_settings, Settings};
+use crate::context::{RequestBody, RequestContext};
 use crate::db::DbPool;
 use crate::handlers::*;
+use crate::listener::ConnectionMetadata;
+use crate::matcher::Matcher;
 use crate::reporter::Report;
+use crate::session::SessionStore;
+use crate::utils::request_target;
+use crate::writer::EventQueue;
 use actix_web::{web, web::Bytes, HttpRequest, HttpResponse, Responder};
+use async_trait::async_trait;
 use ipnetwork::IpNetwork;
 use lazy_static::lazy_static;
-use log::{debug, trace};
-use regex::Regex;
-use std::sync::mpsc;
+use log::{debug, error, info, warn};
+use regex::RegexSet;
+use std::cmp::Reverse;
+use std::sync::{mpsc, Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 lazy_static! {
-    static ref REGISTERED_HANDLERS: Vec<RequestHandler> = {
-        vec![
-            etc_passwd::register(),
-            eval_stdin::register(),
-            cgi_bin::register(),
-            wordpress_login::register(),
-            wordpress_json::register(),
-            wordpress_xmlrpc::register(),
-            wordpress_wlwmanifest::register(),
-            envfile::register(),
-            robots_bait::register(),
-        ]
-    };
-    static ref DEFAULT_HANDLER: RequestHandler =
-        RequestHandler::new("default", Regex::new("").unwrap(), default::handler);
+    static ref REGISTRY: RwLock<Arc<HandlerRegistry>> = RwLock::new(Arc::new(
+        HandlerRegistry::new(Vec::new()).expect("Failed to create handler registry")
+    ));
+    static ref DEFAULT_HANDLER: RequestHandler = RequestHandler::new(
+        default::HANDLER_NAME,
+        i32::MIN,
+        Matcher::Always,
+        default::DefaultHandler
+    );
+}
+
+fn builtin_handlers() -> Vec<RequestHandler> {
+    vec![
+        etc_passwd::register(),
+        eval_stdin::register(),
+        cgi_bin::register(),
+        wordpress_login::register(),
+        wordpress_json::register(),
+        wordpress_xmlrpc::register(),
+        wordpress_wlwmanifest::register(),
+        envfile::register(),
+        robots_bait::register(),
+    ]
+}
+
+/// What a handler adds to the event recorded for a request. The rest of the
+/// event is built from the request by the dispatcher.
+#[derive(Default)]
+pub struct EventDetails {
+    pub subhandler: Option<String>,
+    pub handler_data: Option<String>,
 }
 
 pub struct HandlerResponse {
     pub http_response: HttpResponse,
-    pub handler_event: Option<models::HandlerEvent>,
+    /// Details of the event to record, `None` to record no event
+    pub event: Option<EventDetails>,
     pub report: Option<Report>,
 }
 
@@ -38,13 +62,13 @@ impl HandlerResponse {
     pub fn new(response_content: &'static str) -> Self {
         HandlerResponse {
             http_response: HttpResponse::Ok().body(response_content),
-            handler_event: None,
+            event: None,
             report: None,
         }
     }
 
-    pub fn set_event(mut self, event: models::HandlerEvent) -> Self {
-        self.handler_event = Some(event);
+    pub fn set_event(mut self, event: EventDetails) -> Self {
+        self.event = Some(event);
         self
     }
 
@@ -54,87 +78,290 @@ impl HandlerResponse {
     }
 }
 
-type RequestHandlerFunction = fn(Bytes, &HttpRequest) -> HandlerResponse;
+#[async_trait(?Send)]
+pub trait Handler: Send + Sync {
+    async fn handle(&self, ctx: &RequestContext) -> HandlerResponse;
+}
 
 pub struct RequestHandler {
-    pub name: &'static str,
-    pub pattern: Regex,
-    pub handler: RequestHandlerFunction,
+    pub name: String,
+    pub priority: i32,
+    pub matcher: Matcher,
+    pub handler: Box<dyn Handler>,
 }
 
 impl RequestHandler {
     pub fn new(
-        name: &'static str,
-        pattern: Regex,
-        handler: RequestHandlerFunction,
+        name: &str,
+        priority: i32,
+        matcher: Matcher,
+        handler: impl Handler + 'static,
     ) -> RequestHandler {
         RequestHandler {
-            name,
-            pattern,
-            handler,
+            name: name.to_string(),
+            priority,
+            matcher,
+            handler: Box::new(handler),
         }
     }
 }
 
-pub fn get_header_value(req: &HttpRequest, header: &str) -> Option<String> {
-    req.headers().get(header).map(|val| {
-        val.to_str()
-            .unwrap_or_else(|e| {
-                trace!("Failed to decode header: {}", e);
-                ""
-            })
-            .to_string()
-    })
+/// All known handlers, ordered by descending priority. The URI patterns of
+/// the handlers are compiled into a single `RegexSet`, so the candidates for
+/// a request are found in one pass; only the remaining conditions of each
+/// candidate are then checked, in priority order.
+pub struct HandlerRegistry {
+    handlers: Vec<RequestHandler>,
+    uri_set: RegexSet,
+    uri_set_handlers: Vec<usize>,
+    unfiltered_handlers: Vec<usize>,
 }
 
-pub fn get_peer_address(req: &HttpRequest) -> Option<IpNetwork> {
-    req.peer_addr().map(|addr| IpNetwork::from(addr.ip()))
-}
+impl HandlerRegistry {
+    pub fn new(mut handlers: Vec<RequestHandler>) -> Result<Self, regex::Error> {
+        // Stable sort, so handlers with equal priority keep registration order
+        handlers.sort_by_key(|handler| Reverse(handler.priority));
 
-pub fn get_ip_address(req: &HttpRequest) -> Option<IpNetwork> {
-    match get_header_value(req, "X-Forwarded-For") {
-        Some(ip) => {
-            let last_ip = match ip.rfind(", ") {
-                Some(pos) => &ip[pos + 2..],
-                None => &ip,
-            };
+        let mut patterns = Vec::new();
+        let mut uri_set_handlers = Vec::new();
+        let mut unfiltered_handlers = Vec::new();
+        for (idx, handler) in handlers.iter().enumerate() {
+            match handler.matcher.uri_pattern() {
+                Some(pattern) => {
+                    patterns.push(pattern);
+                    uri_set_handlers.push(idx);
+                }
+                None => unfiltered_handlers.push(idx),
+            }
+        }
+
+        Ok(HandlerRegistry {
+            uri_set: RegexSet::new(patterns)?,
+            handlers,
+            uri_set_handlers,
+            unfiltered_handlers,
+        })
+    }
+
+    /// All handlers, in priority order
+    pub fn handlers(&self) -> &[RequestHandler] {
+        &self.handlers
+    }
 
-            match last_ip.parse::<IpNetwork>() {
-                Ok(ip) => Some(ip),
-                Err(e) => {
-                    trace!("Failed to parse X-Forwarded-For: {}", e);
-                    get_peer_address(req)
+    /// Matching handlers, in priority order, among the `enabled` ones if
+    /// given. The URI patterns are matched by the `RegexSet` in one pass,
+    /// so only the other conditions of these handlers are checked.
+    fn matching<'a: 'r, 'r>(
+        &'a self,
+        req: &'r HttpRequest,
+        bytes: &'r Bytes,
+        enabled: Option<&'r [String]>,
+    ) -> impl Iterator<Item = &'a RequestHandler> + 'r {
+        let uri = request_target(req.uri(), req.version());
+        let mut candidates = self
+            .uri_set
+            .matches(&uri)
+            .into_iter()
+            .map(|idx| (self.uri_set_handlers[idx], true))
+            .chain(self.unfiltered_handlers.iter().map(|idx| (*idx, false)))
+            .collect::<Vec<(usize, bool)>>();
+        candidates.sort_unstable();
+        candidates
+            .into_iter()
+            .map(move |(idx, preselected)| (&self.handlers[idx], preselected))
+            .filter(move |(handler, _)| {
+                enabled.is_none_or(|enabled| enabled.contains(&handler.name))
+            })
+            .filter(move |(handler, preselected)| {
+                if *preselected {
+                    handler.matcher.matches_rest(req, bytes)
+                } else {
+                    handler.matcher.matches(req, bytes)
                 }
+            })
+            .map(|(handler, _)| handler)
+    }
+
+    /// Returns the matching handler with the highest priority, among the
+    /// `enabled` ones if given
+    pub fn find(
+        &self,
+        req: &HttpRequest,
+        bytes: &Bytes,
+        enabled: Option<&[String]>,
+    ) -> Option<&RequestHandler> {
+        self.matching(req, bytes, enabled).next()
+    }
+
+    /// Returns every matching handler, in priority order, among the
+    /// `enabled` ones if given
+    pub fn find_all(
+        &self,
+        req: &HttpRequest,
+        bytes: &Bytes,
+        enabled: Option<&[String]>,
+    ) -> Vec<&RequestHandler> {
+        self.matching(req, bytes, enabled).collect()
+    }
+}
+
+/// Builds the handler registry from the built-in handlers and the ones
+/// defined in the configuration, without making it active, and warns about
+/// unknown handler names. Returns it with the number of handlers defined in
+/// the configuration.
+pub fn build_registry(settings: &Settings) -> Result<(HandlerRegistry, usize), String> {
+    let mut handlers =
+        declarative::load(settings).map_err(|e| format!("invalid handler definition: {}", e))?;
+    let handler_count = handlers.len();
+    handlers.extend(builtin_handlers());
+    let registry = HandlerRegistry::new(handlers)
+        .map_err(|e| format!("failed to compile handler patterns: {}", e))?;
+    check_handler_names(settings, &registry);
+    Ok((registry, handler_count))
+}
+
+/// Warns about the handlers named by personas and by the scoring policy
+/// that don't exist, as those names are most likely typos
+fn check_handler_names(settings: &Settings, registry: &HandlerRegistry) {
+    let exists = |name: &str| registry.handlers.iter().any(|handler| handler.name == name);
+    for persona in &settings.personas {
+        for name in &persona.handlers {
+            if !exists(name) {
+                warn!(
+                    "Persona \"{}\" names unknown handler \"{}\"",
+                    persona.name, name
+                );
+            }
+        }
+    }
+    if let Some(scoring) = &settings.reporting.scoring {
+        for key in scoring.scores.keys() {
+            let name = key.split('/').next().unwrap_or_default();
+            if !exists(name) {
+                warn!(
+                    "Score \"{}\" of reporting.scoring names unknown handler \"{}\"",
+                    key, name
+                );
             }
         }
-        None => get_peer_address(req),
     }
 }
 
+/// Replaces the active registry. Requests already being matched keep the
+/// registry they started with.
+pub fn set_registry(registry: HandlerRegistry) {
+    *write_registry() = Arc::new(registry);
+}
+
+/// Replaces the settings and the registry together, so no request sees the
+/// new settings with the old handlers, or the other way around
+pub fn publish(settings: Settings, registry: HandlerRegistry) {
+    let mut guard = write_registry();
+    configuration::set_settings(settings);
+    *guard = Arc::new(registry);
+}
+
+/// Returns the settings and the registry, as published together
+pub fn get_settings_and_registry() -> (Arc<Settings>, Arc<HandlerRegistry>) {
+    // The registry lock is held while both are replaced
+    let guard = read_registry();
+    (get_settings(), guard.clone())
+}
+
+fn read_registry() -> RwLockReadGuard<'static, Arc<HandlerRegistry>> {
+    REGISTRY.read().unwrap_or_else(|e| {
+        error!("Failed to acquire read lock on handler registry: {}", e);
+        std::process::abort();
+    })
+}
+
+fn write_registry() -> RwLockWriteGuard<'static, Arc<HandlerRegistry>> {
+    REGISTRY.write().unwrap_or_else(|e| {
+        error!("Failed to acquire write lock on handler registry: {}", e);
+        std::process::abort();
+    })
+}
+
+pub fn get_peer_address(req: &HttpRequest) -> Option<IpNetwork> {
+    req.peer_addr().map(|addr| IpNetwork::from(addr.ip()))
+}
+
 pub async fn request_dispatcher(
-    bytes: Bytes,
+    payload: web::Payload,
     req: HttpRequest,
-    db_pool: web::Data<DbPool>,
+    events: web::Data<EventQueue>,
     sender: web::Data<mpsc::Sender<Report>>,
+    sessions: web::Data<SessionStore>,
 ) -> impl Responder {
-    let handler: &RequestHandler = REGISTERED_HANDLERS
-        .iter()
-        .find(|handler| handler.pattern.is_match(&req.uri().to_string()))
-        .unwrap_or(&DEFAULT_HANDLER);
-    let handler_func: RequestHandlerFunction = handler.handler;
+    let (settings, registry) = get_settings_and_registry();
+    let body = match RequestBody::read(payload, settings.http.max_request_size).await {
+        Ok(body) => body,
+        Err(e) => {
+            debug!("Failed to read request body: {}", e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+    let bytes = &body.bytes;
+    let listener = req
+        .extensions()
+        .get::<ConnectionMetadata>()
+        .map(|metadata| metadata.listener.clone());
+    let enabled = listener
+        .as_deref()
+        .and_then(|listener| settings.listener_handlers(listener));
+    let handler: &RequestHandler = if settings.http.debug_matching {
+        let matched = registry.find_all(&req, bytes, enabled);
+        info!(
+            "Handlers matching {} {}: [{}]",
+            req.method(),
+            req.uri(),
+            matched
+                .iter()
+                .map(|handler| format!("{} ({})", handler.name, handler.priority))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        matched.first().copied()
+    } else {
+        registry.find(&req, bytes, enabled)
+    }
+    .unwrap_or(&DEFAULT_HANDLER);
+
+    // Not an extractor, which would log a failure on every request without
+    // a database
+    let db_pool = req.app_data::<web::Data<DbPool>>().cloned();
+    let ctx = RequestContext::new(&handler.name, req, body, db_pool, settings, sessions);
+    ctx.with_session(|session| session.requests += 1);
+    debug!(
+        "{} {} {} from {}",
+        ctx.metadata.method,
+        ctx.metadata.uri,
+        ctx.metadata.http_version,
+        ctx.metadata
+            .peer_addr
+            .map_or_else(|| String::from("unknown peer"), |addr| addr.to_string())
+    );
 
     debug!("Running handler: {}", handler.name);
-    let resp = handler_func(bytes, &req);
+    let resp = handler.handler.handle(&ctx).await;
 
-    if let Some(event) = resp.handler_event {
-        models::HandlerEvent::insert(
-            event,
-            &db_pool.get().expect("Failed to get database connection"),
+    let event_subhandler = resp
+        .event
+        .as_ref()
+        .and_then(|event| event.subhandler.clone());
+    if let Some(details) = resp.event {
+        events.push(
+            ctx.new_event()
+                .set_subhandler(details.subhandler.as_deref())
+                .set_handler_data(details.handler_data),
         );
     }
 
-    if get_settings_reader().reporting_enabled {
-        if let Some(report) = resp.report {
+    if ctx.settings.reporting.enabled {
+        if let Some(mut report) = resp.report {
+            if report.subhandler.is_none() {
+                report.subhandler = event_subhandler;
+            }
             sender
                 .send(report)
                 .expect("Failed to send report to reporter");
@@ -143,3 +370,70 @@ pub async fn request_dispatcher(
 
     resp.http_response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::Method;
+    use actix_web::test::TestRequest;
+
+    fn registry() -> HandlerRegistry {
+        let handler = |name, priority, matcher| {
+            RequestHandler::new(name, priority, matcher, default::DefaultHandler)
+        };
+        HandlerRegistry::new(vec![
+            handler("admin", 10, Matcher::uri("^/admin")),
+            handler(
+                "admin-post",
+                20,
+                Matcher::All(vec![Matcher::uri("^/admin"), Matcher::Method(Method::POST)]),
+            ),
+            handler("get", 5, Matcher::Method(Method::GET)),
+            handler("admin-late", 10, Matcher::uri("^/admin/login")),
+        ])
+        .unwrap()
+    }
+
+    fn names(handlers: Vec<&RequestHandler>) -> Vec<&str> {
+        handlers
+            .iter()
+            .map(|handler| handler.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn finds_handlers_by_priority() {
+        let registry = registry();
+        let body = Bytes::new();
+
+        let get = TestRequest::get().uri("/admin/login").to_http_request();
+        assert_eq!(
+            registry.find(&get, &body, None).map(|h| h.name.as_str()),
+            Some("admin")
+        );
+        assert_eq!(
+            names(registry.find_all(&get, &body, None)),
+            ["admin", "admin-late", "get"]
+        );
+
+        let post = TestRequest::post().uri("/admin").to_http_request();
+        assert_eq!(
+            names(registry.find_all(&post, &body, None)),
+            ["admin-post", "admin"]
+        );
+
+        let other = TestRequest::post().uri("/index.php").to_http_request();
+        assert!(registry.find(&other, &body, None).is_none());
+    }
+
+    #[test]
+    fn finds_enabled_handlers_only() {
+        let registry = registry();
+        let req = TestRequest::get().uri("/admin").to_http_request();
+        let enabled = [String::from("get")];
+        assert_eq!(
+            names(registry.find_all(&req, &Bytes::new(), Some(&enabled))),
+            ["get"]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crate::configuration::Settings;
+use crate::context::{RequestContext, RequestMetadata};
+use crate::handler::{EventDetails, Handler, HandlerResponse, RequestHandler};
+use crate::matcher::MatcherDefinition;
+use crate::reporter::Category;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use async_trait::async_trait;
+use config::{Config, ConfigError, File};
+use log::debug;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::path::Path;
+
+// Handlers defined in the configuration file (`[[handlers]]`) or in the
+// files of the `handlers-dir` directory, compiled at startup
+
+const HANDLER_FILE_EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+const DEFAULT_COMMENT: &str = "{method} {uri}";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HandlerDefinition {
+    pub name: String,
+    #[serde(default = "default_priority")]
+    pub priority: i32,
+    #[serde(flatten)]
+    pub matcher: MatcherDefinition,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    pub body: Option<String>,
+    pub body_file: Option<String>,
+    #[serde(default)]
+    pub categories: Vec<u8>,
+    pub comment: Option<String>,
+}
+
+// Above all built-in handlers, so configured ones can override them
+fn default_priority() -> i32 {
+    100
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+fn default_content_type() -> String {
+    String::from("text/plain;charset=UTF-8")
+}
+
+pub struct DeclarativeHandler {
+    status: StatusCode,
+    content_type: String,
+    body: String,
+    categories: Vec<Category>,
+    comment: String,
+}
+
+#[async_trait(?Send)]
+impl Handler for DeclarativeHandler {
+    async fn handle(&self, ctx: &RequestContext) -> HandlerResponse {
+        HandlerResponse {
+            http_response: HttpResponse::build(self.status)
+                .content_type(self.content_type.as_str())
+                .body(self.body.clone()),
+            event: Some(EventDetails::default()),
+            report: if self.categories.is_empty() {
+                None
+            } else {
+                ctx.new_report().map(|report| {
+                    report
+                        .add_categories(self.categories.iter().copied())
+                        .set_comment_text(render_comment(&self.comment, &ctx.metadata))
+                })
+            },
+        }
+    }
+}
+
+/// Expands the `{method}`, `{uri}`, `{host}` and `{user_agent}` placeholders
+/// of a comment template
+fn render_comment(template: &str, metadata: &RequestMetadata) -> String {
+    template
+        .replace("{method}", &metadata.method)
+        .replace("{uri}", &metadata.uri)
+        .replace("{host}", &metadata.header("Host").unwrap_or_default())
+        .replace(
+            "{user_agent}",
+            &metadata.header("User-Agent").unwrap_or_default(),
+        )
+}
+
+impl HandlerDefinition {
+    pub fn compile(self) -> Result<RequestHandler, String> {
+        let matcher = self
+            .matcher
+            .compile()
+            .map_err(|e| format!("handler \"{}\": {}", self.name, e))?;
+        let status = StatusCode::from_u16(self.status)
+            .map_err(|e| format!("handler \"{}\": invalid status: {}", self.name, e))?;
+        let body = match (self.body, &self.body_file) {
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "handler \"{}\": body and body-file are mutually exclusive",
+                    self.name
+                ))
+            }
+            (Some(body), None) => body,
+            (None, Some(path)) => std::fs::read_to_string(path).map_err(|e| {
+                format!(
+                    "handler \"{}\": failed to read body file \"{}\": {}",
+                    self.name, path, e
+                )
+            })?,
+            (None, None) => String::new(),
+        };
+        let categories = self
+            .categories
+            .iter()
+            .map(|id| Category::try_from(*id))
+            .collect::<Result<Vec<Category>, String>>()
+            .map_err(|e| format!("handler \"{}\": {}", self.name, e))?;
+
+        let handler = DeclarativeHandler {
+            status,
+            content_type: self.content_type,
+            body,
+            categories,
+            comment: self
+                .comment
+                .unwrap_or_else(|| String::from(DEFAULT_COMMENT)),
+        };
+
+        Ok(RequestHandler::new(
+            &self.name,
+            self.priority,
+            matcher,
+            handler,
+        ))
+    }
+}
+
+fn read_definitions(config: &Config) -> Result<Vec<HandlerDefinition>, String> {
+    match config.get::<Vec<HandlerDefinition>>("handlers") {
+        Ok(definitions) => Ok(definitions),
+        Err(ConfigError::NotFound(_)) => Ok(Vec::new()),
+        Err(e) => Err(format!("invalid handler definitions: {}", e)),
+    }
+}
+
+fn read_definitions_dir(dir: &Path) -> Result<Vec<HandlerDefinition>, String> {
+    let mut paths = std::fs::read_dir(dir)
+        .map_err(|e| format!("failed to read handlers directory {:?}: {}", dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| HANDLER_FILE_EXTENSIONS.contains(&ext))
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut definitions = Vec::new();
+    for path in paths {
+        debug!("Loading handler definitions from {:?}", path);
+        let mut file_config = Config::default();
+        file_config
+            .merge(File::from(path.as_path()))
+            .map_err(|e| format!("failed to load {:?}: {}", path, e))?;
+        definitions
+            .extend(read_definitions(&file_config).map_err(|e| format!("{:?}: {}", path, e))?);
+    }
+    Ok(definitions)
+}
+
+/// Builds request handlers from the `[[handlers]]` section of the
+/// configuration and from the files in `handlers-dir`, if set
+pub fn load(settings: &Settings) -> Result<Vec<RequestHandler>, String> {
+    let mut definitions = settings.handlers.clone();
+    if let Some(dir) = &settings.handlers_dir {
+        definitions.extend(read_definitions_dir(Path::new(dir))?);
+    }
+
+    definitions
+        .into_iter()
+        .map(HandlerDefinition::compile)
+        .collect()
+}
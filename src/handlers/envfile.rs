@@ -1,10 +1,11 @@
-use crate::db::models::HandlerEvent;
-use crate::handler::{get_header_value, get_ip_address, HandlerResponse, RequestHandler};
-use crate::reporter::{Category, Report};
-use actix_web::{web::Bytes, HttpRequest};
-use regex::Regex;
+use crate::context::RequestContext;
+use crate::handler::{EventDetails, Handler, HandlerResponse, RequestHandler};
+use crate::matcher::Matcher;
+use crate::reporter::Category;
+use async_trait::async_trait;
 
 pub const HANDLER_NAME: &str = "envfile";
+const PRIORITY: i32 = 20;
 
 pub const RESP_CONTENT: &str = "HTTP_ADMINISTRATION_ENDPOINT = /data/xmlrpc.php
 HTTP_ADMINISTRATION_ENDPOINT_SSL = /data/xmlrpc.php
@@ -12,31 +13,23 @@ HTTP_ADMINISTRATION_ENDPOINT_SSL_PORT = 443
 HTTP_ADMINISTRATION_ENDPOINT_PORT = 80
 HTTP_ADMINISTRATION_TOKEN = admin";
 
-pub fn handler(_bytes: Bytes, req: &HttpRequest) -> HandlerResponse {
-    HandlerResponse::new(RESP_CONTENT)
-        .set_event(
-            HandlerEvent::new(HANDLER_NAME)
-                .set_host(get_header_value(req, "Host"))
-                .set_x_forwarded_for(get_header_value(req, "X-Forwarded-For"))
-                .set_src_ip(get_ip_address(req))
-                .set_user_agent(get_header_value(req, "User-Agent"))
-                .set_uri(req.uri().to_string()),
-        )
-        .set_report(get_ip_address(req).map(|ip| {
-            Report::new(ip)
-                .add_categories(vec![
+pub struct EnvFile;
+
+#[async_trait(?Send)]
+impl Handler for EnvFile {
+    async fn handle(&self, ctx: &RequestContext) -> HandlerResponse {
+        HandlerResponse::new(RESP_CONTENT)
+            .set_event(EventDetails::default())
+            .set_report(ctx.new_report().map(|report| {
+                report.add_categories(vec![
                     Category::Hacking,
                     Category::WebAppAttack,
                     Category::BadWebBot,
                 ])
-                .set_comment_text(format!("{} {}", req.method().as_str(), req.uri()))
-        }))
+            }))
+    }
 }
 
 pub fn register() -> RequestHandler {
-    RequestHandler {
-        name: HANDLER_NAME,
-        pattern: Regex::new("\\.env").expect("Failed to compile regex"),
-        handler,
-    }
+    RequestHandler::new(HANDLER_NAME, PRIORITY, Matcher::uri("\\.env"), EnvFile)
 }
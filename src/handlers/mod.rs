@@ -1,4 +1,5 @@
 pub mod cgi_bin;
+pub mod declarative;
 pub mod default;
 pub mod envfile;
 pub mod etc_passwd;
@@ -1,15 +1,16 @@
-use crate::db::models::HandlerEvent;
-use crate::handler::{get_header_value, get_ip_address, HandlerResponse, RequestHandler};
-use crate::reporter::{Category, Report};
-use actix_web::{web::Bytes, HttpRequest};
+use crate::context::RequestContext;
+use crate::handler::{EventDetails, Handler, HandlerResponse, RequestHandler};
+use crate::matcher::Matcher;
+use crate::reporter::Category;
+use async_trait::async_trait;
 use lazy_static::lazy_static;
-use log::warn;
 use regex::Regex;
 
 // Return a fake robots.txt, which blacklists a specific endpoint
 // Catch everyone trying to access the endpoint
 
 pub const HANDLER_NAME: &str = "robots-bait";
+const PRIORITY: i32 = 10;
 
 pub const ROBOTS_CONTENT: &str = "User-Agent: *
 Disallow: /bb.php";
@@ -20,41 +21,30 @@ lazy_static! {
         Regex::new("^/robots\\.txt").expect("Failed to compile robots.txt pattern regex");
 }
 
-pub fn handler(bytes: Bytes, req: &HttpRequest) -> HandlerResponse {
-    if ROBOTS_PATTERN.is_match(&req.uri().to_string()) {
-        return HandlerResponse::new(ROBOTS_CONTENT);
-    }
+pub struct RobotsBait;
+
+#[async_trait(?Send)]
+impl Handler for RobotsBait {
+    async fn handle(&self, ctx: &RequestContext) -> HandlerResponse {
+        if ROBOTS_PATTERN.is_match(&ctx.metadata.uri) {
+            return HandlerResponse::new(ROBOTS_CONTENT);
+        }
 
-    HandlerResponse::new(ENDPOINT_CONTENT)
-        .set_event(
-            HandlerEvent::new(HANDLER_NAME)
-                .set_host(get_header_value(req, "Host"))
-                .set_x_forwarded_for(get_header_value(req, "X-Forwarded-For"))
-                .set_src_ip(get_ip_address(req))
-                .set_user_agent(get_header_value(req, "User-Agent"))
-                .set_uri(req.uri().to_string())
-                .set_payload(
-                    match (req.method().as_str(), String::from_utf8(bytes.to_vec())) {
-                        ("POST" | "PUT", Ok(text)) => Some(text),
-                        (_, Err(e)) => {
-                            warn!("Failed to decode POST payload: {}", e);
-                            None
-                        }
-                        _ => None,
-                    },
-                ),
-        )
-        .set_report(get_ip_address(req).map(|ip| {
-            Report::new(ip)
-                .add_categories(vec![Category::Hacking, Category::BadWebBot])
-                .set_comment_text(format!("{} {}", req.method().as_str(), req.uri()))
-        }))
+        HandlerResponse::new(ENDPOINT_CONTENT)
+            .set_event(EventDetails::default())
+            .set_report(
+                ctx.new_report().map(|report| {
+                    report.add_categories(vec![Category::Hacking, Category::BadWebBot])
+                }),
+            )
+    }
 }
 
 pub fn register() -> RequestHandler {
-    RequestHandler {
-        name: HANDLER_NAME,
-        pattern: Regex::new("^/robots\\.txt|bb\\.php").expect("Failed to compile regex"),
-        handler,
-    }
+    RequestHandler::new(
+        HANDLER_NAME,
+        PRIORITY,
+        Matcher::uri("^/robots\\.txt|bb\\.php"),
+        RobotsBait,
+    )
 }
@@ -1,14 +1,16 @@
-use crate::db::models::HandlerEvent;
-use crate::handler::{get_header_value, get_ip_address, HandlerResponse, RequestHandler};
-use crate::reporter::{Category, Report};
+use crate::context::RequestContext;
+use crate::handler::{EventDetails, Handler, HandlerResponse, RequestHandler};
+use crate::matcher::Matcher;
+use crate::reporter::Category;
 use crate::utils::generate_random_string;
-use actix_web::{web::Bytes, HttpRequest, HttpResponse};
+use actix_web::HttpResponse;
+use async_trait::async_trait;
 use lazy_static::lazy_static;
-use log::warn;
 use rand::Rng;
 use regex::Regex;
 
 const HANDLER_NAME: &str = "wp-json";
+const PRIORITY: i32 = 50;
 
 struct RESTEndpoint {
     pattern: Regex,
@@ -34,45 +36,33 @@ lazy_static! {
     };
 }
 
-pub fn handler(bytes: Bytes, req: &HttpRequest) -> HandlerResponse {
-    let endpoint_resp = (ENDPOINT_LIST
-        .iter()
-        .find(|endpoint| endpoint.pattern.is_match(req.path()))
-        .unwrap_or(&DEFAULT_ENDPOINT)
-        .response)();
-    HandlerResponse {
-        http_response: HttpResponse::Ok()
-            .content_type("text/html;charset=UTF-8")
-            .body(endpoint_resp.content),
-        handler_event: Some(
-            HandlerEvent::new(HANDLER_NAME)
-                .set_subhandler(endpoint_resp.endpoint)
-                .set_host(get_header_value(req, "Host"))
-                .set_uri(req.uri().to_string())
-                .set_x_forwarded_for(get_header_value(req, "X-Forwarded-For"))
-                .set_src_ip(get_ip_address(req))
-                .set_user_agent(get_header_value(req, "User-Agent"))
-                .set_handler_data(endpoint_resp.details)
-                .set_payload(
-                    match (req.method().as_str(), String::from_utf8(bytes.to_vec())) {
-                        ("POST" | "PUT", Ok(text)) => Some(text),
-                        (_, Err(e)) => {
-                            warn!("Failed to decode POST payload: {}", e);
-                            None
-                        }
-                        _ => None,
-                    },
-                ),
-        ),
-        report: get_ip_address(req).map(|ip| {
-            Report::new(ip)
-                .add_categories(vec![
+pub struct WordpressJson;
+
+#[async_trait(?Send)]
+impl Handler for WordpressJson {
+    async fn handle(&self, ctx: &RequestContext) -> HandlerResponse {
+        let req = &ctx.req;
+        let endpoint_resp = (ENDPOINT_LIST
+            .iter()
+            .find(|endpoint| endpoint.pattern.is_match(req.path()))
+            .unwrap_or(&DEFAULT_ENDPOINT)
+            .response)();
+        HandlerResponse {
+            http_response: HttpResponse::Ok()
+                .content_type("text/html;charset=UTF-8")
+                .body(endpoint_resp.content),
+            event: Some(EventDetails {
+                subhandler: endpoint_resp.endpoint.map(String::from),
+                handler_data: endpoint_resp.details,
+            }),
+            report: ctx.new_report().map(|report| {
+                report.add_categories(vec![
                     Category::Hacking,
                     Category::WebAppAttack,
                     Category::BruteForce,
                 ])
-                .set_comment_text(format!("{} {}", req.method().as_str(), req.uri()))
-        }),
+            }),
+        }
     }
 }
 
@@ -102,9 +92,10 @@ fn get_default_response() -> RESTEndpointResponse {
 }
 
 pub fn register() -> RequestHandler {
-    RequestHandler {
-        name: HANDLER_NAME,
-        pattern: Regex::new("wp-json").expect("Failed to compile regex"),
-        handler,
-    }
+    RequestHandler::new(
+        HANDLER_NAME,
+        PRIORITY,
+        Matcher::uri("wp-json"),
+        WordpressJson,
+    )
 }
@@ -1,10 +1,12 @@
-use crate::db::models::HandlerEvent;
-use crate::handler::{get_header_value, get_ip_address, HandlerResponse, RequestHandler};
-use crate::reporter::{Category, Report};
-use actix_web::{web::Bytes, HttpRequest, HttpResponse};
-use regex::Regex;
+use crate::context::RequestContext;
+use crate::handler::{EventDetails, Handler, HandlerResponse, RequestHandler};
+use crate::matcher::Matcher;
+use crate::reporter::Category;
+use actix_web::HttpResponse;
+use async_trait::async_trait;
 
 pub const HANDLER_NAME: &str = "wp-wlwmanifest";
+const PRIORITY: i32 = 30;
 
 const RESP_CONTENT: &str = "<?xml version=\"1.0\" encoding=\"utf-8\" ?>
 
@@ -51,35 +53,32 @@ const RESP_CONTENT: &str = "<?xml version=\"1.0\" encoding=\"utf-8\" ?>
 </manifest>
 ";
 
-pub fn handler(_bytes: Bytes, req: &HttpRequest) -> HandlerResponse {
-    HandlerResponse {
-        http_response: HttpResponse::Ok()
-            .content_type("application/xml;charset=UTF-8")
-            .body(RESP_CONTENT),
-        handler_event: Some(
-            HandlerEvent::new(HANDLER_NAME)
-                .set_host(get_header_value(req, "Host"))
-                .set_uri(req.uri().to_string())
-                .set_x_forwarded_for(get_header_value(req, "X-Forwarded-For"))
-                .set_src_ip(get_ip_address(req))
-                .set_user_agent(get_header_value(req, "User-Agent")),
-        ),
-        report: get_ip_address(req).map(|ip| {
-            Report::new(ip)
-                .add_categories(vec![
+pub struct WordpressWlwmanifest;
+
+#[async_trait(?Send)]
+impl Handler for WordpressWlwmanifest {
+    async fn handle(&self, ctx: &RequestContext) -> HandlerResponse {
+        HandlerResponse {
+            http_response: HttpResponse::Ok()
+                .content_type("application/xml;charset=UTF-8")
+                .body(RESP_CONTENT),
+            event: Some(EventDetails::default()),
+            report: ctx.new_report().map(|report| {
+                report.add_categories(vec![
                     Category::Hacking,
                     Category::WebAppAttack,
                     Category::BadWebBot,
                 ])
-                .set_comment_text(format!("{} {}", req.method().as_str(), req.uri()))
-        }),
+            }),
+        }
     }
 }
 
 pub fn register() -> RequestHandler {
-    RequestHandler {
-        name: HANDLER_NAME,
-        pattern: Regex::new("wp-includes/wlwmanifest\\.xml").expect("Failed to compile regex"),
-        handler,
-    }
+    RequestHandler::new(
+        HANDLER_NAME,
+        PRIORITY,
+        Matcher::uri("wp-includes/wlwmanifest\\.xml"),
+        WordpressWlwmanifest,
+    )
 }
@@ -0,0 +1,461 @@
+use crate::configuration::ListenerConfig;
+use crate::proxy_protocol;
+use crate::raw_headers::{HeaderQueue, HeaderScanner, Headers};
+use crate::tls::{self, TlsMetadata};
+use actix_http::body::MessageBody;
+use actix_http::error::DispatchError;
+use actix_http::{Error, HttpService, Protocol, Request, Response};
+use actix_rt::net::{TcpStream, UnixStream};
+use actix_server::ServerBuilder;
+use actix_service::{
+    fn_service, map_config, pipeline_factory, IntoServiceFactory, Service, ServiceFactory,
+};
+use actix_web::dev::{AppConfig, ServiceRequest};
+use actix_web::http::uri::{Authority, Scheme, Uri};
+use actix_web::HttpMessage;
+use log::{debug, error, info, warn};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// HTTP listeners. Connections go through a step of our own before the HTTP
+// parser, which reads what comes ahead of HTTP (the PROXY protocol header),
+// terminates TLS, and records what it learned, with the listener, for the
+// request handlers. On HTTP/1, the header blocks of the requests are then
+// recorded as the parser reads them.
+
+/// Time allowed for the PROXY protocol header and the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most of what a client sends before the end of the TLS handshake which is
+/// kept for fingerprinting. A ClientHello fits in much less.
+const MAX_CLIENT_HELLO_SIZE: usize = 65536;
+
+/// What was learned about a connection before HTTP parsing. Stored in the
+/// extensions of every request made on the connection, by `on_connect`:
+/// actix-http moves the extensions set by `on_connect_ext` into the first
+/// request only.
+#[derive(Clone)]
+pub struct ConnectionMetadata {
+    /// Name of the listener that accepted the connection
+    pub listener: String,
+    /// Client address carried by a PROXY protocol header. It replaces the
+    /// peer address of the requests.
+    pub proxy_source: Option<SocketAddr>,
+    /// Local address of the connection, `None` on unix sockets
+    pub local_addr: Option<SocketAddr>,
+    pub tls: Option<TlsMetadata>,
+    /// Headers of the requests not handled yet, as sent. `None` on HTTP/2,
+    /// whose header blocks are compressed.
+    pub header_queue: Option<HeaderQueue>,
+}
+
+/// Stream keeping a copy of the first bytes read from it, until told to stop
+struct Recorder<T> {
+    io: T,
+    recorded: Option<Vec<u8>>,
+}
+
+impl<T> Recorder<T> {
+    fn new(io: T) -> Self {
+        Recorder {
+            io,
+            recorded: Some(Vec::new()),
+        }
+    }
+
+    /// Stops recording, and returns what was recorded
+    fn stop(&mut self) -> Vec<u8> {
+        self.recorded.take().unwrap_or_default()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Recorder<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(read)), Some(recorded)) = (&poll, &mut this.recorded) {
+            let kept = (*read).min(MAX_CLIENT_HELLO_SIZE.saturating_sub(recorded.len()));
+            recorded.extend_from_slice(&buf[..kept]);
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Recorder<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+enum Stream<T> {
+    Plain(T),
+    Tls(Box<TlsStream<Recorder<T>>>),
+}
+
+/// Accepted stream, with its metadata
+pub struct Connection<T> {
+    io: Stream<T>,
+    /// Records the headers of the requests read, on HTTP/1
+    headers: Option<HeaderScanner>,
+    metadata: ConnectionMetadata,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for Connection<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = match &mut this.io {
+            Stream::Plain(io) => Pin::new(io).poll_read(cx, buf),
+            Stream::Tls(io) => Pin::new(io).poll_read(cx, buf),
+        };
+        if let (Poll::Ready(Ok(read)), Some(headers)) = (&poll, &mut this.headers) {
+            headers.feed(&buf[..*read]);
+        }
+        poll
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Connection<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.io {
+            Stream::Plain(io) => Pin::new(io).poll_write(cx, buf),
+            Stream::Tls(io) => Pin::new(io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.io {
+            Stream::Plain(io) => Pin::new(io).poll_flush(cx),
+            Stream::Tls(io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.io {
+            Stream::Plain(io) => Pin::new(io).poll_shutdown(cx),
+            Stream::Tls(io) => Pin::new(io).poll_shutdown(cx),
+        }
+    }
+}
+
+fn timed_out(step: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("timed out waiting for {}", step),
+    )
+}
+
+/// How the connections of a listener are accepted
+#[derive(Clone)]
+struct Acceptor {
+    listener: String,
+    proxy_protocol: bool,
+    tls: Option<TlsAcceptor>,
+}
+
+impl Acceptor {
+    /// Runs the steps preceding HTTP parsing on an accepted stream
+    async fn accept<T: AsyncRead + AsyncWrite + Unpin>(
+        self,
+        mut io: T,
+        peer: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+    ) -> Result<(Connection<T>, Protocol, Option<SocketAddr>), DispatchError> {
+        let mut metadata = ConnectionMetadata {
+            listener: self.listener,
+            proxy_source: None,
+            local_addr,
+            tls: None,
+            header_queue: None,
+        };
+        let mut peer = peer;
+
+        if self.proxy_protocol {
+            let header =
+                actix_rt::time::timeout(HANDSHAKE_TIMEOUT, proxy_protocol::read_header(&mut io))
+                    .await
+                    .unwrap_or_else(|_| Err(timed_out("PROXY protocol header")));
+            match header {
+                Ok(Some(source)) => {
+                    metadata.proxy_source = Some(source);
+                    peer = Some(source);
+                }
+                // Connection from the proxy itself: keep its address
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Dropping connection from {:?}: {}", peer, e);
+                    return Err(DispatchError::Io(e));
+                }
+            }
+        }
+
+        let (io, protocol) = match self.tls {
+            Some(acceptor) => {
+                // The ClientHello is recorded for fingerprinting as rustls
+                // reads it
+                let io = Recorder::new(io);
+                let mut io = actix_rt::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(io))
+                    .await
+                    .unwrap_or_else(|_| Err(timed_out("TLS handshake")))
+                    .map_err(|e| {
+                        debug!("TLS handshake with {:?} failed: {}", peer, e);
+                        DispatchError::Io(e)
+                    })?;
+                let client_hello = io.get_mut().0.stop();
+                let tls = TlsMetadata::new(io.get_ref().1, &client_hello);
+                let protocol = match tls.alpn.as_deref() {
+                    Some("h2") => Protocol::Http2,
+                    _ => Protocol::Http1,
+                };
+                metadata.tls = Some(tls);
+                (Stream::Tls(Box::new(io)), protocol)
+            }
+            None => (Stream::Plain(io), Protocol::Http1),
+        };
+        let headers = match protocol {
+            Protocol::Http1 => {
+                let queue = HeaderQueue::default();
+                metadata.header_queue = Some(queue.clone());
+                Some(HeaderScanner::new(queue))
+            }
+            _ => None,
+        };
+        Ok((
+            Connection {
+                io,
+                headers,
+                metadata,
+            },
+            protocol,
+            peer,
+        ))
+    }
+}
+
+/// Makes the connection info of a request (scheme and host) reflect the
+/// listener it was received on. actix-web 3 only lets its own server build
+/// an `AppConfig`, so every listener has the default one, which says `http`
+/// and `localhost:8080`. The URI is consulted before that config, so the
+/// connection info, which actix caches with the request, is computed with
+/// the scheme and local address of the listener in the URI, which is then
+/// restored.
+pub fn set_connection_info(req: &mut ServiceRequest) {
+    let (secure, local_addr) = match req.extensions().get::<ConnectionMetadata>() {
+        Some(metadata) => (metadata.tls.is_some(), metadata.local_addr),
+        None => return,
+    };
+    let uri = req.head().uri.clone();
+    if uri.scheme().is_some() {
+        return;
+    }
+    let mut parts = uri.clone().into_parts();
+    parts.scheme = Some(if secure { Scheme::HTTPS } else { Scheme::HTTP });
+    parts.authority = match local_addr {
+        Some(addr) => Authority::try_from(addr.to_string().as_str()).ok(),
+        None => Some(Authority::from_static("localhost")),
+    };
+    if let Ok(absolute) = Uri::from_parts(parts) {
+        req.head_mut().uri = absolute;
+        req.connection_info();
+        req.head_mut().uri = uri;
+    }
+}
+
+/// Headers of a request in the order and case they were sent in, `None` if
+/// they weren't recorded. Called once per request, in the order they come.
+pub fn raw_headers(req: &ServiceRequest) -> Option<Headers> {
+    let queue = req
+        .extensions()
+        .get::<ConnectionMetadata>()?
+        .header_queue
+        .clone()?;
+    queue.next(req.headers())
+}
+
+/// Adds a listener to the server: on `host` and `port`, or on the unix
+/// socket at `host` if there is no port
+fn bind_listener<F, I, S, B>(
+    builder: ServerBuilder,
+    listener: &ListenerConfig,
+    factory: F,
+) -> io::Result<ServerBuilder>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S>,
+    S: ServiceFactory<Config = AppConfig, Request = Request>,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let name = listener.name();
+    if listener.proxy_protocol {
+        info!("Expecting PROXY protocol headers on listener \"{}\"", name);
+    }
+    let tls = listener
+        .tls
+        .as_ref()
+        .map(|tls| match tls::server_config(&name, tls) {
+            Ok(config) => TlsAcceptor::from(config),
+            Err(e) => {
+                error!("Failed to set up TLS on listener \"{}\": {}", name, e);
+                std::process::abort();
+            }
+        });
+    let acceptor = Acceptor {
+        listener: name.clone(),
+        proxy_protocol: listener.proxy_protocol,
+        tls,
+    };
+
+    match listener.port {
+        Some(port) => {
+            let addr = match format!("{}:{}", listener.host, port).parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    error!(
+                        "Failed to parse host and port of listener \"{}\": {}",
+                        name, e
+                    );
+                    std::process::abort();
+                }
+            };
+            info!("Binding listener \"{}\" to IP address {}", name, addr);
+            builder.bind(name, addr, move || {
+                let acceptor = acceptor.clone();
+                let service = HttpService::build()
+                    .local_addr(addr)
+                    .on_connect(|io: &Connection<TcpStream>| io.metadata.clone())
+                    .finish(map_config(factory(), |_| AppConfig::default()));
+                pipeline_factory(fn_service(move |io: TcpStream| {
+                    // Responses are written whole, so Nagle only adds latency
+                    let _ = io.set_nodelay(true);
+                    let peer = io.peer_addr().ok();
+                    let local_addr = io.local_addr().ok();
+                    acceptor.clone().accept(io, peer, local_addr)
+                }))
+                .and_then(service)
+            })
+        }
+        None => {
+            warn!(
+                "Binding listener \"{}\" to UNIX socket \"{}\"",
+                name, listener.host
+            );
+            builder.bind_uds(name, &listener.host, move || {
+                let acceptor = acceptor.clone();
+                let service = HttpService::build()
+                    .on_connect(|io: &Connection<UnixStream>| io.metadata.clone())
+                    .finish(map_config(factory(), |_| AppConfig::default()));
+                pipeline_factory(fn_service(move |io: UnixStream| {
+                    acceptor.clone().accept(io, None, None)
+                }))
+                .and_then(service)
+            })
+        }
+    }
+}
+
+/// Adds every listener to the server, all serving the same application
+pub fn bind<F, I, S, B>(
+    mut builder: ServerBuilder,
+    listeners: &[ListenerConfig],
+    factory: F,
+) -> io::Result<ServerBuilder>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S>,
+    S: ServiceFactory<Config = AppConfig, Request = Request>,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    for listener in listeners {
+        builder = bind_listener(builder, listener, factory.clone())?;
+    }
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request(tls: bool, local_addr: Option<&str>, host: Option<&str>) -> ServiceRequest {
+        let mut req = TestRequest::with_uri("/path?query");
+        if let Some(host) = host {
+            req = req.header("Host", host);
+        }
+        let req = req.to_srv_request();
+        req.extensions_mut().insert(ConnectionMetadata {
+            listener: String::from("test"),
+            proxy_source: None,
+            local_addr: local_addr.map(|addr| addr.parse().unwrap()),
+            tls: if tls {
+                Some(TlsMetadata {
+                    sni: None,
+                    alpn: None,
+                    version: None,
+                    ja3: None,
+                    ja4: None,
+                })
+            } else {
+                None
+            },
+            header_queue: None,
+        });
+        req
+    }
+
+    #[test]
+    fn describes_the_listener() {
+        let mut req = request(true, Some("192.0.2.1:8443"), None);
+        set_connection_info(&mut req);
+        assert_eq!(req.connection_info().scheme(), "https");
+        assert_eq!(req.connection_info().host(), "192.0.2.1:8443");
+        assert_eq!(req.uri().to_string(), "/path?query");
+
+        let mut req = request(false, None, None);
+        set_connection_info(&mut req);
+        assert_eq!(req.connection_info().scheme(), "http");
+        assert_eq!(req.connection_info().host(), "localhost");
+
+        // The Host header comes first
+        let mut req = request(true, Some("192.0.2.1:8443"), Some("example.com"));
+        set_connection_info(&mut req);
+        assert_eq!(req.connection_info().host(), "example.com");
+    }
+}
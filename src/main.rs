@@ -1,106 +1,177 @@
 use actix_rt::System;
+use actix_server::Server;
+use actix_web::dev::Service;
 use actix_web::middleware::normalize::TrailingSlash;
-use actix_web::{middleware, web, App, HttpServer};
+use actix_web::{middleware, web, App, HttpMessage};
+use clap::Parser;
+use cli::{Cli, Command, ConfigArgs};
+use context::{RawHeaders, RawUri};
 use env_logger::Env;
 use handler::request_dispatcher;
 use log::{debug, error, info, trace, warn};
 use reporter::Report;
-use std::env;
-use std::net::SocketAddr;
+use session::SessionStore;
 use std::sync::mpsc;
+use std::time::Duration;
+use writer::EventQueue;
 
 #[macro_use]
 extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
+mod admin;
+mod allowlist;
+mod cli;
+mod client_ip;
 mod configuration;
+mod context;
 mod db;
+mod fingerprint;
 mod handler;
 mod handlers;
+mod listener;
+mod matcher;
+mod proxy_protocol;
+mod raw_headers;
 mod reporter;
+mod scoring;
+mod session;
+mod sink;
+mod sinks;
+mod tls;
 mod utils;
+mod writer;
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
+fn main() {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 
-    let args: Vec<String> = env::args().collect();
-    let default_config_file: String = String::from("Config.toml");
-    let config_path = args.get(1).unwrap_or(&default_config_file);
+    let command = Cli::parse().command.unwrap_or_else(|| {
+        Command::Serve(ConfigArgs {
+            config: String::from(cli::DEFAULT_CONFIG),
+        })
+    });
+    let success = match command {
+        Command::Serve(args) => match System::new("devil").block_on(serve(args.config)) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("HTTP server failed: {}", e);
+                false
+            }
+        },
+        Command::Migrate(args) => cli::migrate(&args.config),
+        Command::CheckConfig(args) => cli::check_config(args.config_path()),
+        Command::ListHandlers(args) => cli::list_handlers(&args.config),
+        Command::Export(args) => cli::export(&args),
+        Command::Stats(args) => cli::stats(&args),
+    };
+    if !success {
+        std::process::exit(1);
+    }
+}
 
+async fn serve(config_path: String) -> std::io::Result<()> {
     debug!("Loading configuration from {}", config_path);
-    {
-        let mut config = configuration::CONFIG.write().unwrap_or_else(|e| {
-            error!("Failed to acquire write lock on settings: {}", e);
-            std::process::abort();
-        });
-        config
-            .merge(config::File::with_name(config_path))
-            .unwrap_or_else(|e| {
-                error!("Failed to load config file \"{}\": {}", config_path, e);
-                std::process::abort();
-            });
-        config
-            .merge(config::Environment::with_prefix("DEVIL"))
-            .unwrap_or_else(|e| {
-                error!("Failed to load environment variables: {}", e);
-                std::process::abort();
-            });
+    if let Err(errors) = configuration::load_configuration(&config_path) {
+        for e in errors {
+            error!("Invalid configuration: {}", e);
+        }
+        std::process::abort();
     }
-
-    configuration::load_configuration();
     info!("Loaded configuration");
 
-    let settings = configuration::get_settings_reader();
+    let settings = configuration::get_settings();
     trace!("{:#?}", settings);
 
-    let conn_pool = db::establish_connection();
-    info!("Connected to database");
+    match handler::build_registry(&settings) {
+        Ok((registry, handler_count)) => {
+            info!("Loaded {} handlers from configuration", handler_count);
+            handler::set_registry(registry);
+        }
+        Err(e) => {
+            error!("Failed to load handlers from configuration: {}", e);
+            std::process::abort();
+        }
+    }
 
-    let (tx, rx) = mpsc::channel::<Report>();
-    if settings.reporting_enabled {
-        let reporter_config = reporter::ReporterConfig {
-            api_key: settings.abuseipdb_key.clone().unwrap_or_else(|| {
-                error!("Failed to get abuseipdb-key from config");
-                std::process::abort();
-            }),
-            endpoint: settings.report_endpoint.clone(),
-        };
+    let db_pool = settings.uses_database().then(db::establish_connection);
+    let sinks = sink::load(db_pool.as_ref());
+    info!("Loaded {} event sinks", sinks.len());
+
+    let events = web::Data::new(EventQueue::new(
+        settings.db_config.queue_size,
+        settings.db_config.overflow_policy,
+    ));
+    {
+        let events = events.clone();
+        let batch_size = settings.db_config.batch_size;
+        let flush_interval = Duration::from_millis(settings.db_config.flush_interval);
         std::thread::spawn(move || {
-            info!("Starting reporter thread");
-            let mut sys = System::new("reporter");
-            sys.block_on(reporter::submit_reports(reporter_config, rx));
+            writer::run_writer(&events, sinks, batch_size, flush_interval);
         });
-    } else {
+    }
+
+    // The reporter always runs, as reporting can be enabled by a reload
+    let (tx, rx) = mpsc::channel::<Report>();
+    std::thread::spawn(move || {
+        info!("Starting reporter thread");
+        let mut sys = System::new("reporter");
+        sys.block_on(reporter::submit_reports(rx));
+    });
+    if !settings.reporting.enabled {
         warn!("AbuseIPDB reporting is disabled");
     }
 
+    let sessions = web::Data::new(SessionStore::new(Duration::from_secs(
+        settings.http.session_ttl,
+    )));
+
+    let trailing_slash = settings.http.normalize_path.trailing_slash();
+
+    // Shared with handlers, which may read the database
+    let db_pool = db_pool.map(web::Data::new);
+
     info!("Starting HTTP server");
-    let mut srv = HttpServer::new(move || {
-        App::new()
-            .wrap(middleware::NormalizePath::new(TrailingSlash::Trim))
-            .data(conn_pool.clone())
+    let app_factory = move || {
+        let app = App::new()
+            .wrap(middleware::Condition::new(
+                trailing_slash.is_some(),
+                middleware::NormalizePath::new(trailing_slash.unwrap_or(TrailingSlash::Trim)),
+            ))
+            // Registered last, so it sees the request before normalization
+            .wrap_fn(|mut req, srv| {
+                listener::set_connection_info(&mut req);
+                let raw_uri = RawUri(utils::request_target(req.uri(), req.version()));
+                req.extensions_mut().insert(raw_uri);
+                if let Some(headers) = listener::raw_headers(&req) {
+                    req.extensions_mut().insert(RawHeaders(headers));
+                }
+                srv.call(req)
+            })
+            .app_data(events.clone())
             .data(tx.clone())
-            .default_service(web::route().to(request_dispatcher))
-    })
-    .workers(settings.workers.try_into().unwrap_or(2));
-    srv = if settings.port.is_some() {
-        let port = settings.port.expect("Could not get port from settings");
-        let addr_obj = match format!("{}:{}", settings.host, port).parse::<SocketAddr>() {
-            Ok(addr) => addr,
-            Err(e) => {
-                error!("Failed to parse HTTP host and HTTP port: {}", e);
-                std::process::abort();
-            }
-        };
-        info!("Binding to IP address {}:{}", settings.host, port);
-        srv.bind(addr_obj)?
-    } else {
-        warn!("Binding to UNIX socket \"{}\"", settings.host);
-        srv.bind_uds(&settings.host)?
+            .app_data(sessions.clone());
+        match &db_pool {
+            Some(db_pool) => app.app_data(db_pool.clone()),
+            None => app,
+        }
+        .default_service(web::route().to(request_dispatcher))
     };
+    let srv = listener::bind(
+        Server::build().workers(settings.http.workers),
+        &settings.listeners(),
+        app_factory,
+    )?;
     let run_result = srv.run();
 
+    actix_rt::spawn(admin::reload_on_sighup(config_path.clone()));
+    if let Some(admin_config) = &settings.admin {
+        info!(
+            "Starting admin listener on {}:{}",
+            admin_config.host, admin_config.port
+        );
+        admin::start_server(admin_config, config_path.clone())?;
+    }
+
     run_result.await
 }
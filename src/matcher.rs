@@ -0,0 +1,266 @@
+use crate::utils::request_target;
+use actix_web::http::Method;
+use actix_web::{web, web::Bytes, HttpRequest};
+use regex::bytes::Regex as BytesRegex;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Conditions a request has to satisfy for a handler to be selected.
+/// Matchers can be combined with `All`, `Any` and `Not`.
+pub enum Matcher {
+    Always,
+    Uri(Regex),
+    Method(Method),
+    Header { name: String, value: Regex },
+    Query { name: String, value: Regex },
+    Body(BytesRegex),
+    All(Vec<Matcher>),
+    Any(Vec<Matcher>),
+    Not(Box<Matcher>),
+}
+
+impl Matcher {
+    pub fn uri(pattern: &str) -> Matcher {
+        Matcher::Uri(Regex::new(pattern).expect("Failed to compile regex"))
+    }
+
+    /// URI pattern that every request matched by this matcher satisfies, if
+    /// there is one. Used to preselect handlers with a `RegexSet`.
+    pub fn uri_pattern(&self) -> Option<&str> {
+        match self {
+            Matcher::Uri(pattern) => Some(pattern.as_str()),
+            Matcher::All(matchers) => matchers.iter().find_map(Matcher::uri_pattern),
+            _ => None,
+        }
+    }
+
+    /// Like `matches`, for a request already known to match `uri_pattern`:
+    /// that pattern isn't run again
+    pub fn matches_rest(&self, req: &HttpRequest, body: &Bytes) -> bool {
+        match self {
+            Matcher::Uri(_) => true,
+            Matcher::All(matchers) => {
+                let preselected = matchers.iter().position(|m| m.uri_pattern().is_some());
+                matchers.iter().enumerate().all(|(idx, m)| {
+                    if Some(idx) == preselected {
+                        m.matches_rest(req, body)
+                    } else {
+                        m.matches(req, body)
+                    }
+                })
+            }
+            _ => self.matches(req, body),
+        }
+    }
+
+    pub fn matches(&self, req: &HttpRequest, body: &Bytes) -> bool {
+        match self {
+            Matcher::Always => true,
+            Matcher::Uri(pattern) => pattern.is_match(&request_target(req.uri(), req.version())),
+            Matcher::Method(method) => req.method() == method,
+            Matcher::Header { name, value } => req
+                .headers()
+                .get_all(name.as_str())
+                .filter_map(|header| header.to_str().ok())
+                .any(|header| value.is_match(header)),
+            Matcher::Query { name, value } => {
+                web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+                    .map(|query| {
+                        query.iter().any(|(param, param_value)| {
+                            param == name && value.is_match(param_value)
+                        })
+                    })
+                    .unwrap_or(false)
+            }
+            Matcher::Body(pattern) => pattern.is_match(body),
+            Matcher::All(matchers) => matchers.iter().all(|m| m.matches(req, body)),
+            Matcher::Any(matchers) => matchers.iter().any(|m| m.matches(req, body)),
+            Matcher::Not(matcher) => !matcher.matches(req, body),
+        }
+    }
+}
+
+/// Matcher as written in the configuration file. All the conditions given
+/// in a single definition have to match; `any` holds alternatives of which
+/// at least one has to match.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MatcherDefinition {
+    pub uri: Option<String>,
+    pub method: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub query: HashMap<String, String>,
+    pub payload: Option<String>,
+    #[serde(default)]
+    pub any: Vec<MatcherDefinition>,
+    pub not: Option<Box<MatcherDefinition>>,
+}
+
+impl MatcherDefinition {
+    pub fn compile(&self) -> Result<Matcher, String> {
+        let mut matchers = Vec::new();
+        if let Some(uri) = &self.uri {
+            matchers.push(Matcher::Uri(
+                Regex::new(uri).map_err(|e| format!("invalid uri regex: {}", e))?,
+            ));
+        }
+        if let Some(method) = &self.method {
+            matchers.push(Matcher::Method(
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|e| format!("invalid method: {}", e))?,
+            ));
+        }
+        for (name, value) in &self.headers {
+            matchers.push(Matcher::Header {
+                name: name.clone(),
+                value: Regex::new(value)
+                    .map_err(|e| format!("invalid regex for header {}: {}", name, e))?,
+            });
+        }
+        for (name, value) in &self.query {
+            matchers.push(Matcher::Query {
+                name: name.clone(),
+                value: Regex::new(value)
+                    .map_err(|e| format!("invalid regex for query parameter {}: {}", name, e))?,
+            });
+        }
+        if let Some(payload) = &self.payload {
+            matchers.push(Matcher::Body(
+                BytesRegex::new(payload).map_err(|e| format!("invalid payload regex: {}", e))?,
+            ));
+        }
+        if !self.any.is_empty() {
+            matchers.push(Matcher::Any(
+                self.any
+                    .iter()
+                    .map(MatcherDefinition::compile)
+                    .collect::<Result<Vec<Matcher>, String>>()?,
+            ));
+        }
+        if let Some(not) = &self.not {
+            matchers.push(Matcher::Not(Box::new(not.compile()?)));
+        }
+
+        match matchers.len() {
+            0 => Err(String::from("matcher has no conditions")),
+            1 => Ok(matchers.remove(0)),
+            _ => Ok(Matcher::All(matchers)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn definition(toml: &str) -> MatcherDefinition {
+        let mut config = config::Config::new();
+        config
+            .merge(config::File::from_str(toml, config::FileFormat::Toml))
+            .unwrap();
+        config.try_into().unwrap()
+    }
+
+    #[test]
+    fn matches_each_condition() {
+        let req = TestRequest::post()
+            .uri("/wp-login.php?User=admin")
+            .header("User-Agent", "sqlmap/1.5")
+            .to_http_request();
+        let body = Bytes::from_static(b"log=admin&pwd=hunter2");
+
+        assert!(Matcher::Always.matches(&req, &body));
+        assert!(Matcher::uri(r"^/wp-login\.php").matches(&req, &body));
+        assert!(!Matcher::uri(r"^/xmlrpc\.php").matches(&req, &body));
+        assert!(Matcher::Method(Method::POST).matches(&req, &body));
+        assert!(!Matcher::Method(Method::GET).matches(&req, &body));
+        let header = Matcher::Header {
+            name: String::from("user-agent"),
+            value: Regex::new("^sqlmap/").unwrap(),
+        };
+        assert!(header.matches(&req, &body));
+        let query = |name: &str| Matcher::Query {
+            name: String::from(name),
+            value: Regex::new("^admin$").unwrap(),
+        };
+        assert!(query("User").matches(&req, &body));
+        // Query parameter names are case-sensitive
+        assert!(!query("user").matches(&req, &body));
+        assert!(Matcher::Body(BytesRegex::new("pwd=").unwrap()).matches(&req, &body));
+        assert!(!Matcher::Body(BytesRegex::new("passwd=").unwrap()).matches(&req, &body));
+    }
+
+    #[test]
+    fn combines_conditions() {
+        let req = TestRequest::get().uri("/.env").to_http_request();
+        let body = Bytes::new();
+        let env = || Matcher::uri(r"^/\.env$");
+        let post = || Matcher::Method(Method::POST);
+
+        assert!(!Matcher::All(vec![env(), post()]).matches(&req, &body));
+        assert!(Matcher::Any(vec![env(), post()]).matches(&req, &body));
+        assert!(Matcher::Not(Box::new(post())).matches(&req, &body));
+        assert!(Matcher::All(vec![]).matches(&req, &body));
+        assert!(!Matcher::Any(vec![]).matches(&req, &body));
+    }
+
+    #[test]
+    fn matches_rest_skips_preselected_uri() {
+        let req = TestRequest::get().uri("/other").to_http_request();
+        let body = Bytes::new();
+        let matcher = Matcher::All(vec![Matcher::uri("^/admin$"), Matcher::Method(Method::GET)]);
+        assert_eq!(matcher.uri_pattern(), Some("^/admin$"));
+        assert!(!matcher.matches(&req, &body));
+        assert!(matcher.matches_rest(&req, &body));
+
+        // Only the preselected pattern is skipped
+        let matcher = Matcher::All(vec![Matcher::uri("^/"), Matcher::uri("^/admin$")]);
+        assert!(!matcher.matches_rest(&req, &body));
+        let matcher = Matcher::All(vec![Matcher::uri("^/"), Matcher::Method(Method::POST)]);
+        assert!(!matcher.matches_rest(&req, &body));
+    }
+
+    #[test]
+    fn compiles_definitions() {
+        let matcher = definition(
+            r#"
+            uri = "^/cgi-bin/"
+            method = "post"
+            payload = "echo"
+            not = { headers = { "User-Agent" = "curl" } }
+            "#,
+        )
+        .compile()
+        .unwrap();
+        assert_eq!(matcher.uri_pattern(), Some("^/cgi-bin/"));
+
+        let req = TestRequest::post()
+            .uri("/cgi-bin/test.sh")
+            .header("User-Agent", "Mozilla/5.0")
+            .to_http_request();
+        assert!(matcher.matches(&req, &Bytes::from_static(b"echo hi")));
+        assert!(!matcher.matches(&req, &Bytes::from_static(b"id")));
+
+        let any = definition(
+            r#"
+            any = [{ uri = "^/a$" }, { uri = "^/b$" }]
+            "#,
+        )
+        .compile()
+        .unwrap();
+        assert_eq!(any.uri_pattern(), None);
+        let req = TestRequest::get().uri("/b").to_http_request();
+        assert!(any.matches(&req, &Bytes::new()));
+    }
+
+    #[test]
+    fn rejects_invalid_definitions() {
+        assert!(MatcherDefinition::default().compile().is_err());
+        assert!(definition(r#"uri = "(""#).compile().is_err());
+        assert!(definition(r#"method = "GE T""#).compile().is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// PROXY protocol (https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt),
+// used by load balancers to pass the client address of a TCP connection.
+// The header is read byte-exactly, so the stream can be handed to the HTTP
+// parser right after it.
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the PROXY protocol header (version 1 or 2) at the start of a
+/// connection. Returns the source address it carries, or `None` for
+/// connections made by the proxy itself, such as health checks, and for
+/// address families other than TCP over IPv4 and IPv6.
+pub async fn read_header<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 8];
+    io.read_exact(&mut start).await?;
+    if start.starts_with(V1_PREFIX) {
+        read_v1(io, &start).await
+    } else if start == V2_SIGNATURE[..8] {
+        read_v2(io).await
+    } else {
+        Err(invalid(String::from("missing PROXY protocol header")))
+    }
+}
+
+async fn read_v1<T: AsyncRead + Unpin>(io: &mut T, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid(String::from("PROXY protocol v1 header too long")));
+        }
+        let mut byte = [0u8; 1];
+        io.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8_lossy(&line[..line.len() - 2]);
+
+    let fields = line.split(' ').collect::<Vec<&str>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            match (source.parse::<IpAddr>(), source_port.parse::<u16>()) {
+                (Ok(ip), Ok(port)) => Ok(Some(SocketAddr::new(ip, port))),
+                _ => Err(invalid(format!(
+                    "invalid PROXY protocol v1 header {:?}",
+                    line
+                ))),
+            }
+        }
+        _ => Err(invalid(format!(
+            "invalid PROXY protocol v1 header {:?}",
+            line
+        ))),
+    }
+}
+
+async fn read_v2<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<Option<SocketAddr>> {
+    // The rest of the signature, the version and command, the address
+    // family and the length of what follows
+    let mut rest = [0u8; 8];
+    io.read_exact(&mut rest).await?;
+    if rest[..4] != V2_SIGNATURE[8..] {
+        return Err(invalid(String::from("missing PROXY protocol header")));
+    }
+    let (version_command, family) = (rest[4], rest[5]);
+    let length = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid(format!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        )));
+    }
+
+    // Addresses, followed by TLVs which are skipped
+    let mut addresses = vec![0u8; length];
+    io.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0f {
+        // LOCAL
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        command => {
+            return Err(invalid(format!(
+                "unsupported PROXY protocol command {}",
+                command
+            )))
+        }
+    }
+    match family >> 4 {
+        0x1 if length >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 if length >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        0x1 | 0x2 => Err(invalid(String::from(
+            "truncated addresses in PROXY protocol header",
+        ))),
+        // AF_UNSPEC and AF_UNIX carry no usable address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header, returning what follows it on the stream
+    async fn read(input: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut io = input;
+        let result = read_header(&mut io).await;
+        (result, io.to_vec())
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn addr(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn reads_v1() {
+        let (result, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /").await;
+        assert_eq!(result.unwrap(), addr("192.0.2.1:56324"));
+        assert_eq!(rest, b"GET /");
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n").await;
+        assert_eq!(result.unwrap(), addr("[2001:db8::1]:4000"));
+
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nGET /").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[actix_rt::test]
+    async fn rejects_invalid_v1() {
+        for input in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 example.com 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+        ] {
+            let (result, _) = read(input).await;
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(200, b'1');
+        assert_eq!(
+            read(&long).await.0.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // Cut short before the end of the line
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1").await.0.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[actix_rt::test]
+    async fn reads_v2() {
+        let mut header = v2(
+            0x1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        header.extend_from_slice(b"GET /");
+        let (result, rest) = read(&header).await;
+        assert_eq!(result.unwrap(), addr("192.0.2.1:56324"));
+        assert_eq!(rest, b"GET /");
+
+        let mut addresses = vec![0; 36];
+        addresses[..2].copy_from_slice(&[0x20, 0x01]);
+        addresses[2..4].copy_from_slice(&[0x0d, 0xb8]);
+        addresses[15] = 1;
+        addresses[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        // TLVs following the addresses are skipped
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        let (result, rest) = read(&v2(0x1, 0x21, &addresses)).await;
+        assert_eq!(result.unwrap(), addr("[2001:db8::1]:4000"));
+        assert!(rest.is_empty());
+
+        // LOCAL, from the proxy itself
+        let (result, rest) = read(&v2(0x0, 0x00, &[])).await;
+        assert_eq!(result.unwrap(), None);
+        assert!(rest.is_empty());
+        // AF_UNIX
+        let (result, _) = read(&v2(0x1, 0x31, &[0; 216])).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn rejects_invalid_v2() {
+        let invalid = |header: Vec<u8>| async move { read(&header).await.0.unwrap_err().kind() };
+
+        let mut version = v2(0x1, 0x11, &[0; 12]);
+        version[12] = 0x11;
+        assert_eq!(invalid(version).await, io::ErrorKind::InvalidData);
+        assert_eq!(
+            invalid(v2(0x2, 0x11, &[0; 12])).await,
+            io::ErrorKind::InvalidData
+        );
+        let mut signature = v2(0x1, 0x11, &[0; 12]);
+        signature[10] = b'X';
+        assert_eq!(invalid(signature).await, io::ErrorKind::InvalidData);
+        // Address block too short for its family
+        assert_eq!(
+            invalid(v2(0x1, 0x11, &[0; 8])).await,
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            invalid(v2(0x1, 0x21, &[0; 12])).await,
+            io::ErrorKind::InvalidData
+        );
+        // Stream ending within the header
+        let mut truncated = v2(0x1, 0x11, &[0; 12]);
+        truncated.truncate(20);
+        assert_eq!(invalid(truncated).await, io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            invalid(V2_SIGNATURE[..10].to_vec()).await,
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[actix_rt::test]
+    async fn rejects_missing_header() {
+        let (result, _) = read(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (result, _) = read(b"GET").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,345 @@
+use actix_web::http::HeaderMap;
+use log::debug;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// Headers of HTTP/1 requests in the order and case they were sent in, which
+// tell scanners apart. The HTTP parser keeps headers in a map, losing both,
+// so the bytes read from a connection also go through a scanner of our own.
+// It finds the header block of every request, skipping their bodies, and
+// queues the headers for the requests, which are handled in the order they
+// came in.
+
+/// Longest header block recorded. A longer one stops the recording on its
+/// connection.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Longest line of a chunked body other than data
+const MAX_CHUNK_LINE_SIZE: usize = 4096;
+/// Most header blocks queued ahead of the requests being handled
+const MAX_QUEUED: usize = 32;
+
+pub type Headers = Vec<(String, String)>;
+
+/// Header blocks read on a connection, for its requests not handled yet
+#[derive(Clone, Default)]
+pub struct HeaderQueue(Arc<Mutex<VecDeque<Headers>>>);
+
+impl HeaderQueue {
+    fn push(&self, headers: Headers) -> bool {
+        let mut queue = self.0.lock().expect("header queue lock poisoned");
+        if queue.len() >= MAX_QUEUED {
+            return false;
+        }
+        queue.push_back(headers);
+        true
+    }
+
+    /// Headers of the next request, given the headers it was parsed with.
+    /// `None` if there are none, or if their names differ, in which case the
+    /// scanner lost track of the requests.
+    pub fn next(&self, parsed: &HeaderMap) -> Option<Headers> {
+        let headers = self
+            .0
+            .lock()
+            .expect("header queue lock poisoned")
+            .pop_front()?;
+        let mut names = headers
+            .iter()
+            .map(|(name, _)| name.to_ascii_lowercase())
+            .collect::<Vec<String>>();
+        let mut parsed_names = parsed
+            .iter()
+            .map(|(name, _)| name.as_str().to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        parsed_names.sort();
+        if names != parsed_names {
+            debug!("Recorded header block does not match the request, ignoring it");
+            return None;
+        }
+        Some(headers)
+    }
+}
+
+enum State {
+    /// Reading a header block
+    Head(Vec<u8>),
+    /// Skipping the rest of a body with a `Content-Length`
+    Body(u64),
+    /// Reading the size line of a chunk
+    ChunkSize(Vec<u8>),
+    /// Skipping the rest of a chunk
+    ChunkData(u64),
+    /// Skipping the line break ending a chunk
+    ChunkEnd,
+    /// Reading the trailer section ending a chunked body
+    Trailers(Vec<u8>),
+    /// What follows isn't requests, or couldn't be followed
+    Stopped,
+}
+
+/// Follows the requests in the bytes read from a connection, queuing their
+/// headers
+pub struct HeaderScanner {
+    state: State,
+    queue: HeaderQueue,
+}
+
+impl HeaderScanner {
+    pub fn new(queue: HeaderQueue) -> Self {
+        HeaderScanner {
+            state: State::Head(Vec::new()),
+            queue,
+        }
+    }
+
+    pub fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let (consumed, next) = self.step(data);
+            if let Some(next) = next {
+                self.state = next;
+            }
+            data = &data[consumed..];
+        }
+    }
+
+    /// Consumes the start of `data`, returning how much of it, and the next
+    /// state if it changes
+    fn step(&mut self, data: &[u8]) -> (usize, Option<State>) {
+        match &mut self.state {
+            State::Head(head) => {
+                let byte = data[0];
+                // Empty lines ahead of a request are ignored
+                if head.is_empty() && (byte == b'\r' || byte == b'\n') {
+                    return (1, None);
+                }
+                head.push(byte);
+                if head.ends_with(b"\n\r\n") || head.ends_with(b"\n\n") {
+                    let head = std::mem::take(head);
+                    (1, Some(self.end_of_head(&head)))
+                } else if head.len() > MAX_HEAD_SIZE {
+                    debug!("Header block too long, no longer recording headers");
+                    (1, Some(State::Stopped))
+                } else {
+                    (1, None)
+                }
+            }
+            State::Body(remaining) => {
+                let skipped = data.len().min(*remaining as usize);
+                *remaining -= skipped as u64;
+                let next = (*remaining == 0).then(|| State::Head(Vec::new()));
+                (skipped, next)
+            }
+            State::ChunkSize(line) => {
+                let byte = data[0];
+                if byte != b'\n' {
+                    line.push(byte);
+                    let next = (line.len() > MAX_CHUNK_LINE_SIZE).then_some(State::Stopped);
+                    return (1, next);
+                }
+                let line = String::from_utf8_lossy(line);
+                let size = line.split(';').next().unwrap_or_default().trim();
+                let next = match u64::from_str_radix(size, 16) {
+                    Ok(0) => State::Trailers(Vec::new()),
+                    Ok(size) => State::ChunkData(size),
+                    Err(_) => State::Stopped,
+                };
+                (1, Some(next))
+            }
+            State::ChunkData(remaining) => {
+                let skipped = data.len().min(*remaining as usize);
+                *remaining -= skipped as u64;
+                (skipped, (*remaining == 0).then_some(State::ChunkEnd))
+            }
+            State::ChunkEnd => match data[0] {
+                b'\r' => (1, None),
+                b'\n' => (1, Some(State::ChunkSize(Vec::new()))),
+                _ => (1, Some(State::Stopped)),
+            },
+            State::Trailers(section) => {
+                section.push(data[0]);
+                if section == b"\r\n"
+                    || section == b"\n"
+                    || section.ends_with(b"\n\r\n")
+                    || section.ends_with(b"\n\n")
+                {
+                    (1, Some(State::Head(Vec::new())))
+                } else if section.len() > MAX_HEAD_SIZE {
+                    (1, Some(State::Stopped))
+                } else {
+                    (1, None)
+                }
+            }
+            State::Stopped => (data.len(), None),
+        }
+    }
+
+    /// Queues the headers of a complete header block, and returns the state
+    /// following it
+    fn end_of_head(&self, head: &[u8]) -> State {
+        let (method, headers) = match parse_head(head) {
+            Some(parsed) => parsed,
+            None => return State::Stopped,
+        };
+        let value = |name: &'static str| {
+            headers
+                .iter()
+                .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let chunked = value("Transfer-Encoding")
+            .flat_map(|codings| codings.split(','))
+            .last()
+            .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        let lengths = value("Content-Length")
+            .flat_map(|lengths| lengths.split(','))
+            .map(|length| length.trim().parse::<u64>().ok())
+            .collect::<Vec<Option<u64>>>();
+        // After a CONNECT or an upgrade, what follows isn't HTTP/1
+        let upgrade = method == "CONNECT" || value("Upgrade").next().is_some();
+
+        let next = match (chunked, lengths.first()) {
+            _ if upgrade => State::Stopped,
+            (Some(true), _) => State::ChunkSize(Vec::new()),
+            // Rejected by the HTTP parser
+            (Some(false), _) => State::Stopped,
+            (None, None) => State::Head(Vec::new()),
+            (None, Some(Some(length))) if lengths.iter().all(|l| *l == Some(*length)) => {
+                match length {
+                    0 => State::Head(Vec::new()),
+                    length => State::Body(*length),
+                }
+            }
+            (None, Some(_)) => State::Stopped,
+        };
+        if !self.queue.push(headers) {
+            debug!("Too many requests ahead, no longer recording headers");
+            return State::Stopped;
+        }
+        next
+    }
+}
+
+/// Method and headers of a header block, `None` if it is malformed
+fn parse_head(head: &[u8]) -> Option<(String, Headers)> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split('\n').map(|line| line.trim_end_matches('\r'));
+    let method = lines.next()?.split(' ').next()?.to_string();
+    let mut headers: Headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if line.starts_with([' ', '\t']) {
+            // Obsolete line folding, continuing the previous value
+            let (_, value) = headers.last_mut()?;
+            value.push(' ');
+            value.push_str(line.trim_matches([' ', '\t']));
+            continue;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((
+            name.to_string(),
+            value.trim_matches([' ', '\t']).to_string(),
+        ));
+    }
+    Some((method, headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{HeaderName, HeaderValue};
+
+    fn scan(data: &[u8], piece_size: usize) -> Vec<Headers> {
+        let queue = HeaderQueue::default();
+        let mut scanner = HeaderScanner::new(queue.clone());
+        for piece in data.chunks(piece_size) {
+            scanner.feed(piece);
+        }
+        let headers = queue.0.lock().unwrap().drain(..).collect();
+        headers
+    }
+
+    fn names(headers: &[Headers]) -> Vec<Vec<&str>> {
+        headers
+            .iter()
+            .map(|headers| headers.iter().map(|(name, _)| name.as_str()).collect())
+            .collect()
+    }
+
+    const PIPELINED: &[u8] = b"GET / HTTP/1.1\r\n\
+        User-Agent: scanner\r\n\
+        host: example.com\r\n\
+        Accept: */*\r\n\r\n\
+        POST /login HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Content-Length: 30\r\n\r\n\
+        GET / HTTP/1.1\r\nHost: fake\r\n\r\n\
+        \r\n\
+        POST /upload HTTP/1.1\r\n\
+        Transfer-Encoding: chunked\r\n\
+        HOST: example.com\r\n\r\n\
+        5;ext=1\r\nHost:\r\n\
+        10\r\n0123456789abcdef\r\n\
+        0\r\nX-Trailer: 1\r\n\r\n\
+        GET /last HTTP/1.0\n\
+        X-Folded: a\n \tb\n\n";
+
+    #[test]
+    fn follows_pipelined_requests_and_skips_bodies() {
+        let expected = vec![
+            vec!["User-Agent", "host", "Accept"],
+            vec!["Host", "Content-Length"],
+            vec!["Transfer-Encoding", "HOST"],
+            vec!["X-Folded"],
+        ];
+        for piece_size in [1, 7, PIPELINED.len()] {
+            let headers = scan(PIPELINED, piece_size);
+            assert_eq!(names(&headers), expected, "{}", piece_size);
+            assert_eq!(headers[0][0].1, "scanner");
+            assert_eq!(headers[3][0].1, "a b");
+        }
+    }
+
+    #[test]
+    fn stops_where_requests_cant_be_followed() {
+        let upgrade = b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n\
+            GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(names(&scan(upgrade, 100)), vec![vec!["Upgrade"]]);
+
+        let conflicting = b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n\
+            ab\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(scan(conflicting, 100).len(), 1);
+
+        let long = [b"GET / HTTP/1.1\r\nX: ".as_ref(), &[b'a'; MAX_HEAD_SIZE]].concat();
+        assert!(scan(&long, 1000).is_empty());
+    }
+
+    #[test]
+    fn hands_out_headers_matching_the_request() {
+        let queue = HeaderQueue::default();
+        let mut scanner = HeaderScanner::new(queue.clone());
+        scanner.feed(b"GET / HTTP/1.1\r\nX-B: 1\r\nHost: a\r\nX-B: 2\r\n\r\n");
+        scanner.feed(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+
+        let mut parsed = HeaderMap::new();
+        parsed.insert(
+            HeaderName::from_static("host"),
+            HeaderValue::from_static("a"),
+        );
+        parsed.append(
+            HeaderName::from_static("x-b"),
+            HeaderValue::from_static("1"),
+        );
+        parsed.append(
+            HeaderName::from_static("x-b"),
+            HeaderValue::from_static("2"),
+        );
+        assert_eq!(
+            queue.next(&parsed).unwrap(),
+            [("X-B", "1"), ("Host", "a"), ("X-B", "2")]
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        // Not the headers of this request
+        assert_eq!(queue.next(&parsed), None);
+        assert_eq!(queue.next(&parsed), None);
+    }
+}
@@ -1,33 +1,48 @@
-use actix_web::client::ClientBuilder;
-use chrono::Utc;
+use crate::allowlist::Allowlist;
+use crate::configuration::{get_settings, Settings};
+use crate::db::reports::{self, NewReport, ReportStatus, StoredReport};
+use crate::db::{self, DbConnection};
+use crate::scoring::ScoreBoard;
+use crate::utils::generate_random_string;
+use actix_web::client::{Client, ClientBuilder};
+use actix_web::http::{HeaderMap, StatusCode};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::QueryResult;
 use ipnetwork::IpNetwork;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::sync::mpsc;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{mpsc, Arc};
 use std::time::{Duration, Instant};
 
-pub struct ReporterConfig {
-    pub api_key: String,
-    pub endpoint: String,
-}
-
 pub struct Report {
     pub ip: String,
     pub categories: HashSet<Category>,
     pub comment: Option<String>,
+    /// Handler which made the report, and its subhandler if any. They
+    /// determine the score of the report (`reporting.scoring`).
+    pub handler: String,
+    pub subhandler: Option<String>,
 }
 
 #[allow(dead_code)]
 impl Report {
-    pub fn new(ip: IpNetwork) -> Report {
+    pub fn new(ip: IpNetwork, handler: &str) -> Report {
         Report {
             ip: ip.ip().to_string(),
             categories: HashSet::new(),
             comment: None,
+            handler: handler.to_string(),
+            subhandler: None,
         }
     }
 
+    pub fn set_subhandler(mut self, subhandler: Option<&str>) -> Self {
+        self.subhandler = subhandler.map(|s| s.to_string());
+        self
+    }
+
     pub fn set_comment(mut self, comment: Option<String>) -> Self {
         self.comment = comment;
         self
@@ -82,6 +97,46 @@ pub enum Category {
     IoTTargeted,
 }
 
+impl Category {
+    const ALL: [Category; 23] = [
+        Category::DNSCompromise,
+        Category::DNSPoisoning,
+        Category::FraudOrders,
+        Category::DDoSAttack,
+        Category::FTPBruteForce,
+        Category::PingOfDeath,
+        Category::Phishing,
+        Category::FraudVoIP,
+        Category::OpenProxy,
+        Category::WebSpam,
+        Category::EmailSpam,
+        Category::BlogSpam,
+        Category::VPNAddress,
+        Category::PortScan,
+        Category::Hacking,
+        Category::SQLInjection,
+        Category::Spoofing,
+        Category::BruteForce,
+        Category::BadWebBot,
+        Category::ExploitedHost,
+        Category::WebAppAttack,
+        Category::SSHAttack,
+        Category::IoTTargeted,
+    ];
+}
+
+impl TryFrom<u8> for Category {
+    type Error = String;
+
+    /// Converts an AbuseIPDB category ID into a `Category`
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        id.checked_sub(1)
+            .and_then(|idx| Category::ALL.get(idx as usize))
+            .copied()
+            .ok_or_else(|| format!("unknown AbuseIPDB category {}", id))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ReportHttpBody {
     ip: String,
@@ -89,63 +144,1025 @@ struct ReportHttpBody {
     comment: Option<String>,
 }
 
-pub async fn submit_reports(config: ReporterConfig, receiver: mpsc::Receiver<Report>) {
-    debug!("Submitting reports");
+/// Response of the `/bulk-report` endpoint
+#[derive(Debug, Deserialize)]
+struct BulkReportResponse {
+    data: BulkReportData,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkReportData {
+    saved_reports: usize,
+    #[serde(default)]
+    invalid_reports: Vec<InvalidReport>,
+}
+
+/// Row of a bulk report rejected by AbuseIPDB
+#[derive(Debug, Deserialize)]
+struct InvalidReport {
+    error: String,
+    /// IP address of the row
+    input: String,
+}
+
+/// Reports against an address reported within this window are dropped
+const DEDUP_WINDOW_MINUTES: i64 = 15;
+/// How often queued reports are retried when no new report comes in
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Number of queued reports loaded at a time
+const SEND_BATCH_SIZE: i64 = 100;
+/// Most rows AbuseIPDB accepts in a bulk report
+const BULK_MAX_ROWS: usize = 10000;
+/// Longest comment AbuseIPDB accepts
+const MAX_COMMENT_LENGTH: usize = 1024;
+/// Reports kept in memory while the database is unreachable
+const MAX_UNSTORED_REPORTS: usize = 10000;
+/// Longest wait between two attempts at sending a report
+const MAX_RETRY_DELAY_SECONDS: u64 = 6 * 60 * 60;
+/// Pause when AbuseIPDB limits the rate without saying for how long
+const DEFAULT_PAUSE_SECONDS: i64 = 60 * 60;
+/// Pause when AbuseIPDB refuses the API key, after which it is tried again
+/// in case the key was fixed meanwhile
+const KEY_REFUSED_PAUSE_SECONDS: i64 = 15 * 60;
+
+impl Report {
+    /// Row queuing this report, with the time and address prepended to the
+    /// comment. It is held back until `retry_at`, if set.
+    fn to_queued(&self, retry_at: Option<NaiveDateTime>) -> NewReport {
+        let now = Utc::now();
+        NewReport {
+            created_at: now.naive_utc(),
+            ip: self.ip.clone(),
+            categories: join_categories(self.categories.iter().map(|c| *c as i32)),
+            comment: self.comment.as_ref().map(|comment| {
+                truncate_comment(format!("[{}] {} - {}", now.to_rfc3339(), self.ip, comment))
+            }),
+            status: ReportStatus::Queued.as_str(),
+            attempts: 0,
+            updated_at: now.naive_utc(),
+            retry_at,
+        }
+    }
+}
+
+/// Cuts a comment to the length AbuseIPDB accepts
+fn truncate_comment(comment: String) -> String {
+    match comment.char_indices().nth(MAX_COMMENT_LENGTH) {
+        Some((end, _)) => comment[..end].to_string(),
+        None => comment,
+    }
+}
+
+/// Category IDs in increasing order, comma-separated
+fn join_categories(categories: impl IntoIterator<Item = i32>) -> String {
+    categories
+        .into_iter()
+        .collect::<BTreeSet<i32>>()
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn split_categories(categories: &str) -> impl Iterator<Item = i32> + '_ {
+    categories
+        .split(',')
+        .filter_map(|c| c.trim().parse::<i32>().ok())
+}
+
+/// Row of a bulk report: the queued reports against an address, merged
+struct BulkRow {
+    ip: String,
+    categories: BTreeSet<i32>,
+    first_seen: NaiveDateTime,
+    comments: Vec<String>,
+    /// Queued reports merged into the row, with their attempts so far
+    reports: Vec<(i32, i32)>,
+}
+
+impl BulkRow {
+    /// Comments of the merged reports, cut to the length AbuseIPDB accepts
+    fn comment(&self) -> String {
+        truncate_comment(self.comments.join(" | "))
+    }
+}
+
+/// Merges queued reports by address, in the order addresses first appear
+fn bulk_rows(queued: Vec<StoredReport>) -> Vec<BulkRow> {
+    let mut rows: Vec<BulkRow> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for report in queued {
+        let row = match index.get(&report.ip) {
+            Some(&i) => &mut rows[i],
+            None => {
+                index.insert(report.ip.clone(), rows.len());
+                rows.push(BulkRow {
+                    ip: report.ip.clone(),
+                    categories: BTreeSet::new(),
+                    first_seen: report.created_at,
+                    comments: Vec::new(),
+                    reports: Vec::new(),
+                });
+                rows.last_mut().expect("row was just added")
+            }
+        };
+        row.categories.extend(split_categories(&report.categories));
+        row.first_seen = row.first_seen.min(report.created_at);
+        if let Some(comment) = report.comment {
+            if !row.comments.contains(&comment) {
+                row.comments.push(comment);
+            }
+        }
+        row.reports.push((report.id, report.attempts));
+    }
+    rows
+}
+
+fn csv_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// CSV file for the `/bulk-report` endpoint
+fn bulk_csv(rows: &[BulkRow]) -> String {
+    let mut csv = String::from("IP,Categories,ReportDate,Comment\r\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{}\r\n",
+            row.ip,
+            csv_field(&join_categories(row.categories.iter().copied())),
+            DateTime::<Utc>::from_utc(row.first_seen, Utc).to_rfc3339(),
+            csv_field(&row.comment())
+        ));
+    }
+    csv
+}
 
-    let mut report_timestamps: HashMap<String, Instant> = HashMap::new();
+/// What to do with reports after an attempt at sending them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Sent,
+    /// Rejected for good by a 422 validation error
+    Failed,
+    /// Network error, server error or unexpected response: the reports are
+    /// retried later
+    Retry,
+    /// Rate limited (429): the reports are retried once the pause is over
+    RateLimited,
+    /// API key missing, invalid or revoked (401, 403): the reports stay
+    /// queued and sending pauses until the key can be tried again
+    KeyRefused,
+}
+
+impl Outcome {
+    fn of(status: StatusCode) -> Self {
+        if status.is_success() {
+            Outcome::Sent
+        } else if status == StatusCode::TOO_MANY_REQUESTS {
+            Outcome::RateLimited
+        } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            Outcome::KeyRefused
+        } else if status == StatusCode::UNPROCESSABLE_ENTITY {
+            Outcome::Failed
+        } else {
+            // Anything else says nothing about the report itself, so it is
+            // retried until `max-attempts`
+            Outcome::Retry
+        }
+    }
+}
+
+/// Body of a request to AbuseIPDB
+enum Payload<'a> {
+    /// Single report, to `report-endpoint`
+    Report(&'a ReportHttpBody),
+    /// CSV file, to `bulk-report-endpoint`
+    Bulk(String),
+}
+
+/// An attempt at sending reports, and its result
+struct Attempt {
+    outcome: Outcome,
+    http_status: Option<StatusCode>,
+    /// Body of the response, or the error if there is none
+    response: Option<String>,
+    headers: HeaderMap,
+}
+
+impl Attempt {
+    fn describe(&self) -> String {
+        match self.http_status {
+            Some(status) => status.to_string(),
+            None => self.response.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// State of the reporter between two passes over the queue
+struct Reporter {
+    client: Client,
+    /// Set while AbuseIPDB asks not to be sent reports
+    paused_until: Option<NaiveDateTime>,
+    /// When the next bulk report is due, in bulk mode
+    next_bulk: Instant,
+}
+
+/// Time given by a `Retry-After` header, in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let value = headers.get("Retry-After")?.to_str().ok()?.trim();
+    match value.parse::<i64>() {
+        Ok(seconds) => Some(now + chrono::Duration::seconds(seconds.max(0))),
+        Err(_) => DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| date.naive_utc()),
+    }
+}
 
-    let endpoint_url = config.endpoint.as_str();
-    let client = ClientBuilder::default()
-        .header("Accept", "application/json")
-        .header("Key", config.api_key)
-        .finish();
+/// Time the daily quota is reset, given by `X-RateLimit-Reset` as a Unix
+/// timestamp
+fn quota_reset(headers: &HeaderMap) -> Option<NaiveDateTime> {
+    let value = headers.get("X-RateLimit-Reset")?.to_str().ok()?;
+    NaiveDateTime::from_timestamp_opt(value.trim().parse::<i64>().ok()?, 0)
+}
+
+/// Whether `X-RateLimit-Remaining` says the quota is exhausted
+fn quota_exhausted(headers: &HeaderMap) -> bool {
+    headers
+        .get("X-RateLimit-Remaining")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<i64>().ok())
+        .is_some_and(|remaining| remaining <= 0)
+}
 
-    while let Ok(msg) = receiver.recv() {
-        if match report_timestamps.get(&msg.ip) {
-            Some(timestamp) => Instant::now().duration_since(*timestamp) < Duration::from_secs(900),
+/// Delay before the next attempt at a report which failed `attempts`
+/// times: `retry_delay` doubled on every attempt, with jitter so retries
+/// don't all come at once
+fn backoff(retry_delay: u64, attempts: i32) -> chrono::Duration {
+    let exponent = (attempts.max(1) - 1).min(16) as u32;
+    let delay = retry_delay
+        .saturating_mul(2u64.pow(exponent))
+        .min(MAX_RETRY_DELAY_SECONDS);
+    let jittered = delay / 2 + thread_rng().gen_range(0..=delay - delay / 2);
+    chrono::Duration::seconds(jittered as i64)
+}
+
+impl Reporter {
+    /// Whether sending is paused. Ends the pause once it is over.
+    fn is_paused(&mut self) -> bool {
+        match self.paused_until {
+            Some(until) if until > Utc::now().naive_utc() => true,
+            Some(_) => {
+                info!("Resuming reports");
+                self.paused_until = None;
+                false
+            }
             None => false,
-        } {
-            debug!("Skipping report for {} - rate limit", msg.ip);
-            continue;
         }
-        report_timestamps.insert(msg.ip.clone(), Instant::now());
+    }
+
+    /// Pauses sending until `until`, holding back the queued reports
+    fn pause(&mut self, conn: &DbConnection, until: NaiveDateTime) {
+        self.paused_until = Some(until);
+        if let Err(e) = reports::postpone_queued(conn, until) {
+            error!("Failed to postpone queued reports: {}", e);
+        }
+    }
+
+    /// How long to wait for new reports before the next pass over the queue
+    fn wait_time(&self, settings: &Settings) -> Duration {
+        match settings.reporting.bulk_window {
+            Some(_) => POLL_INTERVAL.min(self.next_bulk.saturating_duration_since(Instant::now())),
+            None => POLL_INTERVAL,
+        }
+    }
 
-        let http_report = ReportHttpBody {
-            ip: msg.ip.clone(),
-            categories: msg
+    /// Whether a report against `ip` with `categories` adds nothing to what
+    /// is queued or was recently reported. In bulk mode, reports are merged
+    /// by address, so a report is only dropped if a queued report already
+    /// has all its categories.
+    fn is_duplicate(&self, conn: &DbConnection, report: &Report, bulk: bool) -> QueryResult<bool> {
+        let since = Utc::now().naive_utc() - chrono::Duration::minutes(DEDUP_WINDOW_MINUTES);
+        if reports::is_reported(conn, &report.ip, since)? {
+            return Ok(true);
+        }
+        let queued = reports::queued_categories(conn, &report.ip)?;
+        if !bulk {
+            return Ok(!queued.is_empty());
+        }
+        Ok(queued.iter().any(|categories| {
+            let queued = split_categories(categories).collect::<BTreeSet<i32>>();
+            report
                 .categories
-                .into_iter()
-                .map(|c| (c as i32).to_string())
-                .collect::<Vec<String>>()
-                .join(","),
-            comment: match msg.comment {
-                Some(comment) => Some(format!(
-                    "[{}] {} - {}",
-                    Utc::now().to_rfc3339(),
-                    msg.ip,
-                    comment
-                )),
-                None => None,
-            },
+                .iter()
+                .all(|category| queued.contains(&(*category as i32)))
+        }))
+    }
+
+    /// Queues reports in the database, oldest first, unless they duplicate
+    /// one or are allowlisted. The allowlist is checked last, as verifying
+    /// crawlers takes DNS lookups. Stops at the first database error,
+    /// leaving the rest for a later attempt.
+    async fn queue_reports(
+        &self,
+        conn: &DbConnection,
+        unstored: &mut VecDeque<Report>,
+        allowlist: &mut Allowlist,
+    ) {
+        let settings = get_settings();
+        let bulk = settings.reporting.bulk_window.is_some();
+        while let Some(report) = unstored.front() {
+            let result = match self.is_duplicate(conn, report, bulk) {
+                Ok(true) => {
+                    debug!("Skipping report for {} - rate limit", report.ip);
+                    Ok(0)
+                }
+                Ok(false) => match allowlist
+                    .check(&report.ip, &settings.reporting.allowlist)
+                    .await
+                {
+                    Some(reason) => {
+                        info!("Not reporting {}: {}", report.ip, reason);
+                        Ok(0)
+                    }
+                    None => reports::insert(conn, &report.to_queued(self.paused_until)),
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("Failed to queue report for {}: {}", report.ip, e);
+                return;
+            }
+            unstored.pop_front();
+        }
+    }
+
+    /// Settings to send reports with, unless sending is disabled or paused
+    fn sending_settings(&mut self) -> Option<Arc<Settings>> {
+        let settings = get_settings();
+        if !settings.reporting.enabled || self.is_paused() {
+            return None;
+        }
+        if settings.reporting.abuseipdb_key.is_none() {
+            error!("Failed to get abuseipdb-key from config");
+            return None;
+        }
+        Some(settings)
+    }
+
+    async fn post(&self, settings: &Settings, payload: Payload<'_>) -> Attempt {
+        let api_key = settings.reporting.abuseipdb_key.as_deref().unwrap_or("");
+        let result = match payload {
+            Payload::Report(body) => {
+                self.client
+                    .post(&settings.reporting.report_endpoint)
+                    .header("Key", api_key)
+                    .send_json(body)
+                    .await
+            }
+            Payload::Bulk(csv) => {
+                let boundary = generate_random_string(32);
+                let body = format!(
+                    "--{boundary}\r\n\
+                     Content-Disposition: form-data; name=\"csv\"; filename=\"report.csv\"\r\n\
+                     Content-Type: text/csv\r\n\r\n\
+                     {csv}\r\n\
+                     --{boundary}--\r\n",
+                    boundary = boundary,
+                    csv = csv
+                );
+                self.client
+                    .post(&settings.reporting.bulk_report_endpoint)
+                    .header("Key", api_key)
+                    .content_type(format!("multipart/form-data; boundary={}", boundary))
+                    .send_body(body)
+                    .await
+            }
         };
+        match result {
+            Ok(mut response) => {
+                let body = response
+                    .body()
+                    .limit(BULK_MAX_ROWS * 1024)
+                    .await
+                    .map(|body| String::from_utf8_lossy(&body).into_owned())
+                    .ok();
+                Attempt {
+                    outcome: Outcome::of(response.status()),
+                    http_status: Some(response.status()),
+                    response: body,
+                    headers: response.headers().clone(),
+                }
+            }
+            Err(e) => Attempt {
+                outcome: Outcome::Retry,
+                http_status: None,
+                response: Some(e.to_string()),
+                headers: HeaderMap::new(),
+            },
+        }
+    }
 
-        match client.post(endpoint_url).send_json(&http_report).await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    info!("Successfully submitted report for {}", http_report.ip);
-                } else {
+    /// Records an attempt at sending `reports` (IDs and attempts so far),
+    /// described as `what` in logs. Returns whether to go on sending.
+    fn record(
+        &mut self,
+        conn: &DbConnection,
+        settings: &Settings,
+        reports: &[(i32, i32)],
+        what: &str,
+        attempt: &Attempt,
+    ) -> bool {
+        let now = Utc::now().naive_utc();
+        let ids = reports.iter().map(|(id, _)| *id).collect::<Vec<i32>>();
+        let http_status = attempt.http_status.map(|status| status.as_u16() as i32);
+        let response = attempt.response.as_deref();
+        let result = match attempt.outcome {
+            Outcome::Sent => {
+                info!("Successfully submitted {}", what);
+                reports::record_attempts(
+                    conn,
+                    &ids,
+                    ReportStatus::Sent,
+                    http_status,
+                    response,
+                    None,
+                )
+            }
+            Outcome::Failed => {
+                error!("Failed to submit {}: {}", what, attempt.describe());
+                reports::record_attempts(
+                    conn,
+                    &ids,
+                    ReportStatus::Failed,
+                    http_status,
+                    response,
+                    None,
+                )
+            }
+            Outcome::Retry => {
+                let max_attempts = settings.reporting.max_attempts as i32;
+                let (given_up, retried): (Vec<&(i32, i32)>, _) = reports
+                    .iter()
+                    .partition(|(_, attempts)| attempts + 1 >= max_attempts);
+                let attempts = retried.iter().map(|(_, attempts)| attempts + 1).max();
+                let retry_at = attempts
+                    .map(|attempts| now + backoff(settings.reporting.retry_delay, attempts));
+                match retry_at {
+                    Some(retry_at) => warn!(
+                        "Failed to submit {}, retrying at {}: {}",
+                        what,
+                        retry_at,
+                        attempt.describe()
+                    ),
+                    None => error!(
+                        "Failed to submit {}, giving up after {} attempts: {}",
+                        what,
+                        max_attempts,
+                        attempt.describe()
+                    ),
+                }
+                if retry_at.is_some() && !given_up.is_empty() {
                     error!(
-                        "Failed to submit report for {}: {} ({})",
-                        http_report.ip,
-                        response.status(),
-                        response.status().canonical_reason().unwrap_or("Unknown")
+                        "Giving up on {} reports after {} attempts",
+                        given_up.len(),
+                        max_attempts
                     );
                 }
+                let given_up = given_up.iter().map(|(id, _)| *id).collect::<Vec<i32>>();
+                let retried = retried.iter().map(|(id, _)| *id).collect::<Vec<i32>>();
+                reports::record_attempts(
+                    conn,
+                    &given_up,
+                    ReportStatus::Failed,
+                    http_status,
+                    response,
+                    None,
+                )
+                .and_then(|_| {
+                    reports::record_attempts(
+                        conn,
+                        &retried,
+                        ReportStatus::Queued,
+                        http_status,
+                        response,
+                        retry_at,
+                    )
+                })
+            }
+            // Held back with the rest of the queue below
+            Outcome::RateLimited | Outcome::KeyRefused => reports::record_attempts(
+                conn,
+                &ids,
+                ReportStatus::Queued,
+                http_status,
+                response,
+                None,
+            ),
+        };
+        if let Err(e) = result {
+            error!("Failed to record {}: {}", what, e);
+            return false;
+        }
+
+        let headers = &attempt.headers;
+        match attempt.outcome {
+            Outcome::RateLimited => {
+                let until = retry_after(headers, now)
+                    .or_else(|| quota_reset(headers))
+                    .unwrap_or_else(|| now + chrono::Duration::seconds(DEFAULT_PAUSE_SECONDS));
+                warn!(
+                    "AbuseIPDB rate limit reached, pausing reports until {}",
+                    until
+                );
+                self.pause(conn, until);
+                false
+            }
+            Outcome::KeyRefused => {
+                let until = now + chrono::Duration::seconds(KEY_REFUSED_PAUSE_SECONDS);
+                error!(
+                    "AbuseIPDB refused the API key ({}), check reporting.abuseipdb-key; \
+                     pausing reports until {}",
+                    attempt.describe(),
+                    until
+                );
+                self.pause(conn, until);
+                false
+            }
+            // AbuseIPDB can't be reached, or has trouble
+            Outcome::Retry => false,
+            _ if quota_exhausted(headers) => {
+                let until = quota_reset(headers)
+                    .unwrap_or_else(|| now + chrono::Duration::seconds(DEFAULT_PAUSE_SECONDS));
+                warn!(
+                    "AbuseIPDB daily quota used up, pausing reports until {}",
+                    until
+                );
+                self.pause(conn, until);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Sends the queued reports which are due, oldest first, one request
+    /// per report. The API key, endpoint and retry settings are read from
+    /// the current settings, so they follow configuration reloads. Stops
+    /// when AbuseIPDB can't be reached or limits the rate.
+    async fn send_queued(&mut self, conn: &DbConnection) {
+        let settings = match self.sending_settings() {
+            Some(settings) => settings,
+            None => return,
+        };
+
+        loop {
+            let queued = match reports::load_queued(conn, SEND_BATCH_SIZE) {
+                Ok(queued) => queued,
+                Err(e) => {
+                    error!("Failed to load queued reports: {}", e);
+                    return;
+                }
+            };
+            if queued.is_empty() {
+                return;
             }
-            Err(e) => {
-                error!("Failed to submit report for {}: {}", http_report.ip, e);
+
+            for report in queued {
+                let http_report = ReportHttpBody {
+                    ip: report.ip,
+                    categories: report.categories,
+                    comment: report.comment,
+                };
+                let attempt = self.post(&settings, Payload::Report(&http_report)).await;
+                let what = format!("report for {}", http_report.ip);
+                if !self.record(
+                    conn,
+                    &settings,
+                    &[(report.id, report.attempts)],
+                    &what,
+                    &attempt,
+                ) {
+                    return;
+                }
             }
+        }
+    }
+
+    /// Sends the queued reports which are due with bulk reports, once per
+    /// `bulk-window`, merging the reports against every address into a
+    /// single row
+    async fn send_bulk(&mut self, conn: &DbConnection, window: Duration) {
+        if Instant::now() < self.next_bulk {
+            return;
+        }
+        self.next_bulk = Instant::now() + window;
+        let settings = match self.sending_settings() {
+            Some(settings) => settings,
+            None => return,
         };
+
+        loop {
+            // Rows are merged by address, so there are at most as many of
+            // them as reports
+            let queued = match reports::load_queued(conn, BULK_MAX_ROWS as i64) {
+                Ok(queued) => queued,
+                Err(e) => {
+                    error!("Failed to load queued reports: {}", e);
+                    return;
+                }
+            };
+            if queued.is_empty() {
+                return;
+            }
+
+            let rows = bulk_rows(queued);
+            let attempt = self.post(&settings, Payload::Bulk(bulk_csv(&rows))).await;
+            let what = format!("bulk report of {} addresses", rows.len());
+
+            // Rows AbuseIPDB rejected fail on their own
+            let invalid = match attempt.outcome {
+                Outcome::Sent => attempt
+                    .response
+                    .as_deref()
+                    .and_then(|body| serde_json::from_str::<BulkReportResponse>(body).ok())
+                    .map(|response| {
+                        debug!(
+                            "AbuseIPDB saved {} reports out of {}",
+                            response.data.saved_reports,
+                            rows.len()
+                        );
+                        response
+                            .data
+                            .invalid_reports
+                            .into_iter()
+                            .map(|invalid| (invalid.input, invalid.error))
+                            .collect::<HashMap<String, String>>()
+                    })
+                    .unwrap_or_default(),
+                _ => HashMap::new(),
+            };
+            for row in rows.iter().filter(|row| invalid.contains_key(&row.ip)) {
+                error!(
+                    "AbuseIPDB rejected the report for {}: {}",
+                    row.ip, invalid[&row.ip]
+                );
+                let ids = row.reports.iter().map(|(id, _)| *id).collect::<Vec<i32>>();
+                if let Err(e) = reports::record_attempts(
+                    conn,
+                    &ids,
+                    ReportStatus::Failed,
+                    attempt.http_status.map(|status| status.as_u16() as i32),
+                    Some(&invalid[&row.ip]),
+                    None,
+                ) {
+                    error!("Failed to record report for {}: {}", row.ip, e);
+                    return;
+                }
+            }
+
+            let reports = rows
+                .iter()
+                .filter(|row| !invalid.contains_key(&row.ip))
+                .flat_map(|row| row.reports.iter().copied())
+                .collect::<Vec<(i32, i32)>>();
+            if !self.record(conn, &settings, &reports, &what, &attempt) {
+                return;
+            }
+        }
+    }
+}
+
+/// Queues the reports sent by the dispatcher in the database, and submits
+/// the queued reports, one by one or in bulk (`reporting.bulk-window`).
+/// Reports left queued by a previous run are submitted first.
+pub async fn submit_reports(receiver: mpsc::Receiver<Report>) {
+    debug!("Submitting reports");
+
+    let pool = db::create_pool(&get_settings().db_config);
+    let mut migrated = false;
+    let mut unstored: VecDeque<Report> = VecDeque::new();
+    let mut score_board = ScoreBoard::new();
+    let mut allowlist = Allowlist::new();
+    let mut reporter = Reporter {
+        client: ClientBuilder::default()
+            .header("Accept", "application/json")
+            .finish(),
+        paused_until: None,
+        next_bulk: Instant::now(),
+    };
+
+    loop {
+        let settings = get_settings();
+        match pool.get() {
+            Ok(conn) => {
+                if !migrated {
+                    // The database may not have been reachable by the event
+                    // writer
+                    db::run_migrations_if_enabled(&conn);
+                    migrated = true;
+                }
+                reporter
+                    .queue_reports(&conn, &mut unstored, &mut allowlist)
+                    .await;
+                match settings.reporting.bulk_window {
+                    Some(window) => reporter.send_bulk(&conn, Duration::from_secs(window)).await,
+                    None => reporter.send_queued(&conn).await,
+                }
+            }
+            Err(e) if !unstored.is_empty() => warn!(
+                "Failed to get database connection, {} reports waiting to be queued \
+                 before they can be sent: {}",
+                unstored.len(),
+                e
+            ),
+            Err(_) => {}
+        }
+
+        let received = match receiver.recv_timeout(reporter.wait_time(&settings)) {
+            Ok(report) => Some(report),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        let settings = get_settings();
+        for report in received.into_iter().chain(receiver.try_iter()) {
+            if let Some(report) = score_board.add(report, settings.reporting.scoring.as_ref()) {
+                unstored.push_back(report);
+            }
+        }
+        if unstored.len() > MAX_UNSTORED_REPORTS {
+            let dropped = unstored.len() - MAX_UNSTORED_REPORTS;
+            unstored.drain(..dropped);
+            error!("Dropped {} reports waiting for the database", dropped);
+        }
     }
     info!("Reporter thread exiting");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{DatabaseBackend, DatabaseConfig};
+    use crate::db::schema::reports as reports_table;
+    use actix_web::{web, App, HttpResponse};
+    use diesel::RunQueryDsl;
+    use std::fs;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                actix_web::http::HeaderName::from_static(name),
+                value.parse().unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn only_validation_errors_fail_for_good() {
+        let outcomes = [
+            (200, Outcome::Sent),
+            (422, Outcome::Failed),
+            (429, Outcome::RateLimited),
+            (401, Outcome::KeyRefused),
+            (403, Outcome::KeyRefused),
+            (400, Outcome::Retry),
+            (404, Outcome::Retry),
+            (408, Outcome::Retry),
+            (503, Outcome::Retry),
+        ];
+        for (status, outcome) in outcomes {
+            let status = StatusCode::from_u16(status).unwrap();
+            assert_eq!(Outcome::of(status), outcome, "{}", status);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_limit() {
+        for _ in 0..100 {
+            let first = backoff(60, 1).num_seconds();
+            assert!((30..=60).contains(&first), "{}", first);
+            let third = backoff(60, 3).num_seconds();
+            assert!((120..=240).contains(&third), "{}", third);
+            let last = backoff(60, 1000).num_seconds() as u64;
+            assert!(
+                (MAX_RETRY_DELAY_SECONDS / 2..=MAX_RETRY_DELAY_SECONDS).contains(&last),
+                "{}",
+                last
+            );
+        }
+        assert_eq!(backoff(0, 5).num_seconds(), 0);
+        assert!(backoff(u64::MAX, 2).num_seconds() as u64 <= MAX_RETRY_DELAY_SECONDS);
+    }
+
+    #[test]
+    fn reads_retry_after_and_quota_headers() {
+        let now = NaiveDateTime::from_timestamp_opt(1_600_000_000, 0).unwrap();
+        let seconds = headers(&[("retry-after", " 120 ")]);
+        assert_eq!(
+            retry_after(&seconds, now),
+            Some(now + chrono::Duration::seconds(120))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "-5")]), now),
+            Some(now)
+        );
+        let date = headers(&[("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert_eq!(
+            retry_after(&date, now),
+            NaiveDateTime::from_timestamp_opt(1_445_412_480, 0)
+        );
+        assert_eq!(retry_after(&headers(&[("retry-after", "soon")]), now), None);
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+
+        let quota = headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1600003600"),
+        ]);
+        assert!(quota_exhausted(&quota));
+        assert_eq!(
+            quota_reset(&quota),
+            NaiveDateTime::from_timestamp_opt(1_600_003_600, 0)
+        );
+        assert!(!quota_exhausted(&headers(&[(
+            "x-ratelimit-remaining",
+            "3"
+        )])));
+        assert!(!quota_exhausted(&HeaderMap::new()));
+    }
+
+    fn queued(id: i32, ip: &str, categories: &str, minute: u32, comment: &str) -> StoredReport {
+        let created_at = chrono::NaiveDate::from_ymd_opt(2024, 5, 1)
+            .and_then(|day| day.and_hms_opt(12, minute, 0))
+            .unwrap();
+        StoredReport {
+            id,
+            created_at,
+            ip: ip.to_string(),
+            categories: categories.to_string(),
+            comment: Some(comment.to_string()),
+            status: ReportStatus::Queued.as_str().to_string(),
+            attempts: id % 2,
+            updated_at: created_at,
+            http_status: None,
+            response: None,
+            retry_at: None,
+        }
+    }
+
+    #[test]
+    fn merges_bulk_rows_by_address() {
+        let rows = bulk_rows(vec![
+            queued(1, "192.0.2.1", "21,15", 10, "probe"),
+            queued(2, "192.0.2.2", "14", 11, "scan"),
+            queued(3, "192.0.2.1", "19, 21", 5, "crawl"),
+            queued(4, "192.0.2.1", "21", 20, "probe"),
+        ]);
+        assert_eq!(rows.len(), 2);
+
+        let row = &rows[0];
+        assert_eq!(row.ip, "192.0.2.1");
+        assert_eq!(
+            row.categories.iter().copied().collect::<Vec<_>>(),
+            [15, 19, 21]
+        );
+        assert_eq!(row.first_seen, queued(3, "", "", 5, "").created_at);
+        assert_eq!(row.comment(), "probe | crawl");
+        assert_eq!(row.reports, [(1, 1), (3, 1), (4, 0)]);
+
+        assert_eq!(rows[1].ip, "192.0.2.2");
+        assert_eq!(rows[1].reports, [(2, 0)]);
+    }
+
+    #[test]
+    fn writes_bulk_csv() {
+        let mut rows = bulk_rows(vec![
+            queued(1, "192.0.2.1", "21,15", 10, "said \"hi\", left"),
+            queued(
+                2,
+                "2001:db8::1",
+                "14",
+                11,
+                &"x".repeat(MAX_COMMENT_LENGTH + 10),
+            ),
+        ]);
+        assert_eq!(
+            bulk_csv(&rows[..1]),
+            "IP,Categories,ReportDate,Comment\r\n\
+             192.0.2.1,\"15,21\",2024-05-01T12:10:00+00:00,\"said \"\"hi\"\", left\"\r\n"
+        );
+
+        // Comments are cut to the length AbuseIPDB accepts
+        let csv = bulk_csv(&rows.split_off(1));
+        let row = csv.lines().nth(1).unwrap();
+        let comment = format!("\"{}\"", "x".repeat(MAX_COMMENT_LENGTH));
+        assert_eq!(
+            row,
+            format!("2001:db8::1,\"14\",2024-05-01T12:11:00+00:00,{}", comment)
+        );
+    }
+
+    fn reporter() -> Reporter {
+        Reporter {
+            client: Client::default(),
+            paused_until: None,
+            next_bulk: Instant::now(),
+        }
+    }
+
+    /// Stand-in for AbuseIPDB answering with the status in the path
+    fn mock_endpoint() -> actix_web::test::TestServer {
+        actix_web::test::start(|| {
+            App::new().route(
+                "/{status}",
+                web::post().to(|status: web::Path<u16>| {
+                    let status = StatusCode::from_u16(status.into_inner()).unwrap();
+                    let mut response = HttpResponse::build(status);
+                    if status == StatusCode::TOO_MANY_REQUESTS {
+                        response.header("Retry-After", "120");
+                    }
+                    response.body("{}")
+                }),
+            )
+        })
+    }
+
+    fn settings(endpoint: String) -> Settings {
+        let mut settings = Settings::default();
+        settings.reporting.enabled = true;
+        settings.reporting.abuseipdb_key = Some("key".to_string());
+        settings.reporting.report_endpoint = endpoint;
+        settings.reporting.max_attempts = 3;
+        settings
+    }
+
+    fn stored(conn: &DbConnection) -> StoredReport {
+        match conn {
+            DbConnection::Sqlite(conn) => reports_table::table.first(&**conn).unwrap(),
+            DbConnection::Postgres(_) => unreachable!(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn records_the_outcome_of_each_response() {
+        let dir = std::env::temp_dir().join(format!("devil-reporter-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let server = mock_endpoint();
+        let body = ReportHttpBody {
+            ip: "192.0.2.1".to_string(),
+            categories: "21".to_string(),
+            comment: None,
+        };
+
+        let cases = [
+            (200u16, ReportStatus::Sent, false),
+            (422, ReportStatus::Failed, false),
+            (503, ReportStatus::Queued, false),
+            (429, ReportStatus::Queued, true),
+            (401, ReportStatus::Queued, true),
+            (403, ReportStatus::Queued, true),
+        ];
+        for (i, (status, expected, paused)) in cases.into_iter().enumerate() {
+            let pool = db::create_pool(&DatabaseConfig {
+                backend: DatabaseBackend::Sqlite,
+                db_path: dir
+                    .join(format!("{}.sqlite3", i))
+                    .to_string_lossy()
+                    .into_owned(),
+                ..DatabaseConfig::default()
+            });
+            let conn = pool.get().unwrap();
+            db::run_migrations(&conn).unwrap();
+            let report = Report::new("192.0.2.1".parse().unwrap(), "test")
+                .add_category(Category::WebAppAttack);
+            reports::insert(&conn, &report.to_queued(None)).unwrap();
+            let id = stored(&conn).id;
+
+            let settings = settings(server.url(&format!("/{}", status)));
+            let mut reporter = reporter();
+            let attempt = reporter.post(&settings, Payload::Report(&body)).await;
+            let go_on = reporter.record(&conn, &settings, &[(id, 0)], "report", &attempt);
+
+            let report = stored(&conn);
+            assert_eq!(report.status, expected.as_str(), "{}", status);
+            assert_eq!(report.http_status, Some(i32::from(status)));
+            assert_eq!(reporter.paused_until.is_some(), paused, "{}", status);
+            assert_eq!(go_on, status == 200 || status == 422, "{}", status);
+            // Held back while paused, or retried after a delay
+            assert_eq!(
+                report.retry_at.is_some(),
+                expected == ReportStatus::Queued,
+                "{}",
+                status
+            );
+        }
+
+        // The rate limit pause lasts as long as asked
+        let settings = settings(server.url("/429"));
+        let attempt = reporter().post(&settings, Payload::Report(&body)).await;
+        let until = retry_after(&attempt.headers, Utc::now().naive_utc()).unwrap();
+        assert!(until > Utc::now().naive_utc() + chrono::Duration::seconds(110));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
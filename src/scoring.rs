@@ -0,0 +1,235 @@
+use crate::configuration::ScoringConfig;
+use crate::reporter::Report;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// Scoring policy for reports (`reporting.scoring`). The reports against an
+// address are held back, each adding the score of its handler, until the
+// address reaches the threshold within the window. A single report then
+// goes out with everything gathered, so one-off crawlers stumbling on a
+// trap aren't reported.
+
+/// Report held back, with its score
+struct Hit {
+    time: Instant,
+    score: u32,
+    report: Report,
+}
+
+/// Reports held back, by address. Only used by the reporter thread.
+pub struct ScoreBoard {
+    hits: HashMap<String, Vec<Hit>>,
+    last_prune: Instant,
+}
+
+impl ScoreBoard {
+    pub fn new() -> Self {
+        ScoreBoard {
+            hits: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Adds a report to the score of its address. Returns the report to
+    /// send once the address reaches the threshold, or right away without
+    /// a scoring policy.
+    pub fn add(&mut self, report: Report, scoring: Option<&ScoringConfig>) -> Option<Report> {
+        let scoring = match scoring {
+            Some(scoring) => scoring,
+            None => return Some(report),
+        };
+        let window = Duration::from_secs(scoring.window);
+        self.prune(window);
+
+        let score = scoring.score(&report.handler, report.subhandler.as_deref());
+        if score == 0 {
+            debug!(
+                "Ignoring report for {} by {}: no score",
+                report.ip, report.handler
+            );
+            return None;
+        }
+        let ip = report.ip.clone();
+        let now = Instant::now();
+        let hits = self.hits.entry(ip.clone()).or_default();
+        hits.retain(|hit| now.duration_since(hit.time) < window);
+        hits.push(Hit {
+            time: now,
+            score,
+            report,
+        });
+
+        let total = hits.iter().map(|hit| hit.score).sum::<u32>();
+        if total < scoring.threshold {
+            debug!(
+                "Holding back report for {}: score {} of {}",
+                ip, total, scoring.threshold
+            );
+            return None;
+        }
+        self.hits
+            .remove(&ip)
+            .map(|hits| merge(hits, total, scoring.window))
+    }
+
+    /// Forgets the addresses without hits in the window, at most once per
+    /// window
+    fn prune(&mut self, window: Duration) {
+        if self.last_prune.elapsed() < window {
+            return;
+        }
+        self.last_prune = Instant::now();
+        self.hits
+            .retain(|_, hits| hits.last().is_some_and(|hit| hit.time.elapsed() < window));
+    }
+}
+
+/// Merges the reports against an address into one, with the categories of
+/// all of them. Its comment sums up the handlers which fired and what they
+/// were sent. A lone report is kept as it is.
+fn merge(mut hits: Vec<Hit>, total: u32, window: u64) -> Report {
+    if hits.len() == 1 {
+        return hits.remove(0).report;
+    }
+
+    let mut handlers: Vec<(String, usize)> = Vec::new();
+    let mut comments: Vec<String> = Vec::new();
+    let mut categories = HashSet::new();
+    for hit in &hits {
+        let label = match &hit.report.subhandler {
+            Some(subhandler) => format!("{}/{}", hit.report.handler, subhandler),
+            None => hit.report.handler.clone(),
+        };
+        match handlers.iter_mut().find(|(name, _)| *name == label) {
+            Some((_, count)) => *count += 1,
+            None => handlers.push((label, 1)),
+        }
+        if let Some(comment) = &hit.report.comment {
+            if !comments.contains(comment) {
+                comments.push(comment.clone());
+            }
+        }
+        categories.extend(hit.report.categories.iter().copied());
+    }
+    let handlers = handlers
+        .iter()
+        .map(|(name, count)| match count {
+            1 => name.clone(),
+            count => format!("{} x{}", name, count),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let last = hits.pop().expect("hits are never empty").report;
+    Report {
+        ip: last.ip,
+        categories,
+        comment: Some(format!(
+            "Score {} within {}s ({}): {}",
+            total,
+            window,
+            handlers,
+            comments.join("; ")
+        )),
+        handler: last.handler,
+        subhandler: last.subhandler,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reporter::Category;
+
+    fn scoring(threshold: u32, window: u64, default_score: Option<u32>) -> ScoringConfig {
+        ScoringConfig {
+            threshold,
+            window,
+            default_score,
+            scores: [("scanner", 3), ("scanner/admin", 6), ("favicon", 0)]
+                .into_iter()
+                .map(|(name, score)| (name.to_string(), score))
+                .collect(),
+        }
+    }
+
+    fn report(handler: &str, subhandler: Option<&str>, comment: &str) -> Report {
+        Report::new("192.0.2.1".parse().unwrap(), handler)
+            .set_subhandler(subhandler)
+            .set_comment_text(comment.to_string())
+            .add_category(Category::WebAppAttack)
+    }
+
+    #[test]
+    fn scores_by_subhandler_then_handler_then_default() {
+        let config = scoring(10, 60, Some(1));
+        assert_eq!(config.score("scanner", Some("admin")), 6);
+        assert_eq!(config.score("scanner", Some("other")), 3);
+        assert_eq!(config.score("scanner", None), 3);
+        assert_eq!(config.score("favicon", None), 0);
+        assert_eq!(config.score("unknown", None), 1);
+        // Reported right away without a default score
+        assert_eq!(scoring(10, 60, None).score("unknown", None), 10);
+    }
+
+    #[test]
+    fn holds_reports_back_until_the_threshold() {
+        let scoring = scoring(10, 60, None);
+        let mut board = ScoreBoard::new();
+        assert!(board
+            .add(report("scanner", None, "a"), Some(&scoring))
+            .is_none());
+        assert!(board
+            .add(report("scanner", None, "a"), Some(&scoring))
+            .is_none());
+        let merged = board
+            .add(
+                report("scanner", Some("admin"), "b").add_category(Category::BruteForce),
+                Some(&scoring),
+            )
+            .unwrap();
+        assert_eq!(
+            merged.comment.as_deref(),
+            Some("Score 12 within 60s (scanner x2, scanner/admin): a; b")
+        );
+        assert_eq!(
+            merged.categories,
+            [Category::WebAppAttack, Category::BruteForce].into()
+        );
+        assert_eq!(merged.subhandler.as_deref(), Some("admin"));
+
+        // Starts over once reported
+        assert!(board
+            .add(report("scanner", None, "a"), Some(&scoring))
+            .is_none());
+    }
+
+    #[test]
+    fn forgets_hits_outside_the_window() {
+        let scoring = scoring(6, 0, None);
+        let mut board = ScoreBoard::new();
+        assert!(board
+            .add(report("scanner", None, "a"), Some(&scoring))
+            .is_none());
+        assert!(board
+            .add(report("scanner", None, "a"), Some(&scoring))
+            .is_none());
+        assert_eq!(board.hits["192.0.2.1"].len(), 1);
+    }
+
+    #[test]
+    fn reports_right_away_or_never() {
+        let mut board = ScoreBoard::new();
+        // A lone report reaching the threshold is kept as it is
+        let lone = board
+            .add(report("unknown", None, "a"), Some(&scoring(10, 60, None)))
+            .unwrap();
+        assert_eq!(lone.comment.as_deref(), Some("a"));
+        assert!(board
+            .add(report("favicon", None, "a"), Some(&scoring(10, 60, None)))
+            .is_none());
+        assert!(board.hits.is_empty());
+        assert!(board.add(report("favicon", None, "a"), None).is_some());
+    }
+}
@@ -0,0 +1,89 @@
+use log::error;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// State kept for a single source IP address between requests
+pub struct Session {
+    pub last_seen: Instant,
+    pub requests: u64,
+    pub data: HashMap<String, String>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            last_seen: Instant::now(),
+            requests: 0,
+            data: HashMap::new(),
+        }
+    }
+
+    /// Increments a numeric value stored in the session data, returning the
+    /// new value
+    pub fn increment(&mut self, key: &str) -> u64 {
+        let value = self
+            .data
+            .get(key)
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(0)
+            + 1;
+        self.data.insert(key.to_string(), value.to_string());
+        value
+    }
+}
+
+/// Per-IP sessions, shared by all workers. Sessions that have been idle for
+/// longer than the TTL are discarded.
+pub struct SessionStore {
+    sessions: Mutex<HashMap<IpAddr, Session>>,
+    last_prune: Mutex<Instant>,
+    ttl: Duration,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        SessionStore {
+            sessions: Mutex::new(HashMap::new()),
+            last_prune: Mutex::new(Instant::now()),
+            ttl,
+        }
+    }
+
+    /// Runs `f` on the session of `ip`, creating it if it doesn't exist or
+    /// has expired
+    pub fn with_session<T>(&self, ip: IpAddr, f: impl FnOnce(&mut Session) -> T) -> T {
+        self.prune();
+
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| {
+            error!("Failed to acquire session lock: {}", e);
+            std::process::abort();
+        });
+        let session = sessions.entry(ip).or_insert_with(Session::new);
+        if session.last_seen.elapsed() > self.ttl {
+            *session = Session::new();
+        }
+        session.last_seen = Instant::now();
+        f(session)
+    }
+
+    fn prune(&self) {
+        let mut last_prune = self.last_prune.lock().unwrap_or_else(|e| {
+            error!("Failed to acquire session lock: {}", e);
+            std::process::abort();
+        });
+        if last_prune.elapsed() < self.ttl {
+            return;
+        }
+        *last_prune = Instant::now();
+        drop(last_prune);
+
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| {
+            error!("Failed to acquire session lock: {}", e);
+            std::process::abort();
+        });
+        let ttl = self.ttl;
+        sessions.retain(|_, session| session.last_seen.elapsed() <= ttl);
+    }
+}
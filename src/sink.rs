@@ -0,0 +1,155 @@
+use crate::configuration::get_settings;
+use crate::db::models::HandlerEvent;
+use crate::db::spool::Spool;
+use crate::db::{establish_connection, DbPool};
+use crate::sinks::database::DatabaseSink;
+use crate::sinks::file::FileSink;
+use crate::sinks::stdout::StdoutSink;
+use crate::sinks::syslog::{SyslogSink, Transport};
+use serde::Deserialize;
+
+/// A destination for events. Sinks are driven by the writer thread, so they
+/// may block.
+pub trait EventSink: Send {
+    fn name(&self) -> &'static str;
+
+    /// Writes a batch of events. Called at least once per flush interval,
+    /// possibly with no events, so sinks can retry pending work.
+    fn write(&mut self, events: &[HandlerEvent]) -> Result<(), String>;
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SinkDefinition {
+    /// Handlers whose events go to the sink; all of them if empty
+    #[serde(default)]
+    pub handlers: Vec<String>,
+    #[serde(default)]
+    pub exclude_handlers: Vec<String>,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum SinkKind {
+    /// The database configured under `[db]`
+    #[serde(alias = "postgres")]
+    Database,
+    File(FileSinkDefinition),
+    Syslog(SyslogSinkDefinition),
+    Stdout,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileSinkDefinition {
+    pub path: String,
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_max_files")]
+    pub max_files: u32,
+}
+
+fn default_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_files() -> u32 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SyslogSinkDefinition {
+    pub transport: Transport,
+    pub address: String,
+    /// Syslog facility code, local0 by default
+    #[serde(default = "default_facility")]
+    pub facility: u8,
+    #[serde(default = "default_app_name")]
+    pub app_name: String,
+}
+
+fn default_facility() -> u8 {
+    16
+}
+
+fn default_app_name() -> String {
+    String::from("devil")
+}
+
+/// A sink with the handlers whose events it receives
+pub struct FilteredSink {
+    handlers: Vec<String>,
+    exclude_handlers: Vec<String>,
+    sink: Box<dyn EventSink>,
+}
+
+impl FilteredSink {
+    fn is_filtered(&self) -> bool {
+        !self.handlers.is_empty() || !self.exclude_handlers.is_empty()
+    }
+
+    fn accepts(&self, event: &HandlerEvent) -> bool {
+        (self.handlers.is_empty() || self.handlers.contains(&event.handler))
+            && !self.exclude_handlers.contains(&event.handler)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.sink.name()
+    }
+
+    pub fn write(&mut self, events: &[HandlerEvent]) -> Result<(), String> {
+        if !self.is_filtered() {
+            return self.sink.write(events);
+        }
+        let events = events
+            .iter()
+            .filter(|event| self.accepts(event))
+            .cloned()
+            .collect::<Vec<HandlerEvent>>();
+        self.sink.write(&events)
+    }
+}
+
+/// Builds the sinks listed under `[[sinks]]`. Without that key, events go to
+/// the database only, through `db_pool`.
+pub fn load(db_pool: Option<&DbPool>) -> Vec<FilteredSink> {
+    let settings = get_settings();
+    let default_sinks = [SinkDefinition {
+        handlers: Vec::new(),
+        exclude_handlers: Vec::new(),
+        kind: SinkKind::Database,
+    }];
+    settings
+        .sinks
+        .as_deref()
+        .unwrap_or(&default_sinks)
+        .iter()
+        .map(|definition| {
+            let sink: Box<dyn EventSink> = match &definition.kind {
+                SinkKind::Database => Box::new(DatabaseSink::new(
+                    db_pool.cloned().unwrap_or_else(establish_connection),
+                    Spool::new(&settings.db_config.spool_dir),
+                    settings.db_config.batch_size,
+                )),
+                SinkKind::File(file) => {
+                    Box::new(FileSink::new(&file.path, file.max_size, file.max_files))
+                }
+                SinkKind::Syslog(syslog) => Box::new(SyslogSink::new(
+                    syslog.transport,
+                    &syslog.address,
+                    syslog.facility,
+                    &syslog.app_name,
+                )),
+                SinkKind::Stdout => Box::new(StdoutSink),
+            };
+            FilteredSink {
+                handlers: definition.handlers.clone(),
+                exclude_handlers: definition.exclude_handlers.clone(),
+                sink,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,100 @@
+use crate::db::models::HandlerEvent;
+use crate::db::spool::Spool;
+use crate::db::{run_migrations_if_enabled, DbPool};
+use crate::sink::EventSink;
+use log::{debug, error, info, warn};
+use std::time::{Duration, Instant};
+
+/// How long to wait before trying the database again after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Writes events to the `handler_events` table of the configured database.
+/// While the database is unreachable, events go to the spool, which is
+/// replayed before any new event is written.
+pub struct DatabaseSink {
+    pool: DbPool,
+    spool: Spool,
+    batch_size: usize,
+    db_available: bool,
+    next_attempt: Instant,
+}
+
+impl DatabaseSink {
+    pub fn new(pool: DbPool, spool: Spool, batch_size: usize) -> Self {
+        DatabaseSink {
+            pool,
+            spool,
+            batch_size,
+            db_available: true,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Replays the spool, then writes the batch. Returns `false` if the
+    /// database is unavailable.
+    fn write_batch(&self, batch: &[HandlerEvent]) -> bool {
+        let conn = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("Failed to get database connection: {}", e);
+                return false;
+            }
+        };
+        if !self.db_available {
+            // The database may have been down since startup
+            run_migrations_if_enabled(&conn);
+        }
+
+        if self.spool.is_pending() && !self.spool.replay(&conn, self.batch_size) {
+            return false;
+        }
+        if batch.is_empty() {
+            return true;
+        }
+
+        debug!("Writing {} events", batch.len());
+        match HandlerEvent::insert_batch(batch, &conn) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Error inserting {} events: {}", batch.len(), e);
+                false
+            }
+        }
+    }
+}
+
+impl EventSink for DatabaseSink {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    fn write(&mut self, events: &[HandlerEvent]) -> Result<(), String> {
+        if events.is_empty() && !self.spool.is_pending() {
+            return Ok(());
+        }
+
+        let written =
+            (self.db_available || Instant::now() >= self.next_attempt) && self.write_batch(events);
+        if written {
+            if !self.db_available {
+                info!("Database available again");
+                self.db_available = true;
+            }
+            return Ok(());
+        }
+
+        if self.db_available {
+            warn!("Database unavailable, spooling events");
+            self.db_available = false;
+        }
+        if Instant::now() >= self.next_attempt {
+            self.next_attempt = Instant::now() + RETRY_INTERVAL;
+        }
+        if events.is_empty() {
+            return Ok(());
+        }
+        self.spool
+            .append(events)
+            .map_err(|e| format!("failed to spool events: {}", e))
+    }
+}
@@ -0,0 +1,166 @@
+use crate::db::models::HandlerEvent;
+use crate::sink::EventSink;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Appends events to a file, one JSON object per line. Once the file grows
+/// past `max_size` bytes it is renamed to `<path>.1`, older files shifting
+/// up to `<path>.<max_files>`; anything older is deleted.
+pub struct FileSink {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    file: Option<File>,
+    size: u64,
+}
+
+impl FileSink {
+    pub fn new(path: &str, max_size: u64, max_files: u32) -> Self {
+        FileSink {
+            path: PathBuf::from(path),
+            max_size,
+            max_files,
+            file: None,
+            size: 0,
+        }
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            self.size = fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+        }
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+            self.size = 0;
+        }
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => self.file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            ),
+        };
+        file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl EventSink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn write(&mut self, events: &[HandlerEvent]) -> Result<(), String> {
+        for event in events {
+            let mut line = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+            line.push(b'\n');
+            self.append(&line)
+                .map_err(|e| format!("failed to write to {:?}: {}", self.path, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("devil-file-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Handlers of the events in a file, `None` if it doesn't exist
+    fn handlers(path: &Path) -> Option<Vec<String>> {
+        let contents = fs::read_to_string(path).ok()?;
+        let handlers = contents
+            .lines()
+            .map(|line| {
+                let event: serde_json::Value = serde_json::from_str(line).unwrap();
+                event["handler"].as_str().unwrap().to_string()
+            })
+            .collect();
+        Some(handlers)
+    }
+
+    fn write(sink: &mut FileSink, handlers: &[&str]) {
+        for handler in handlers {
+            assert_eq!(sink.write(&[HandlerEvent::new(handler)]), Ok(()));
+        }
+    }
+
+    #[test]
+    fn shifts_rotated_files_and_deletes_the_oldest() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("events.log");
+        // Every event goes to a file of its own
+        let mut sink = FileSink::new(path.to_str().unwrap(), 1, 2);
+        write(&mut sink, &["a", "b", "c", "d"]);
+
+        assert_eq!(handlers(&path).unwrap(), ["d"]);
+        assert_eq!(handlers(&sink.rotated_path(1)).unwrap(), ["c"]);
+        assert_eq!(handlers(&sink.rotated_path(2)).unwrap(), ["b"]);
+        assert_eq!(handlers(&sink.rotated_path(3)), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn deletes_the_file_without_rotated_files() {
+        let dir = temp_dir("no-rotated");
+        let path = dir.join("events.log");
+        let mut sink = FileSink::new(path.to_str().unwrap(), 1, 0);
+        write(&mut sink, &["a", "b", "c"]);
+
+        assert_eq!(handlers(&path).unwrap(), ["c"]);
+        assert_eq!(handlers(&sink.rotated_path(1)), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn appends_after_rotation_and_reopening() {
+        let dir = temp_dir("reopen");
+        let path = dir.join("events.log");
+        let line_size = serde_json::to_vec(&HandlerEvent::new("a")).unwrap().len() as u64 + 1;
+        let mut sink = FileSink::new(path.to_str().unwrap(), 2 * line_size, 3);
+        write(&mut sink, &["a", "b", "c", "d"]);
+        assert_eq!(handlers(&path).unwrap(), ["c", "d"]);
+        assert_eq!(handlers(&sink.rotated_path(1)).unwrap(), ["a", "b"]);
+
+        // A new sink picks up the size of the file it appends to
+        let mut sink = FileSink::new(path.to_str().unwrap(), 3 * line_size, 3);
+        write(&mut sink, &["e", "f"]);
+        assert_eq!(handlers(&path).unwrap(), ["f"]);
+        assert_eq!(handlers(&sink.rotated_path(1)).unwrap(), ["c", "d", "e"]);
+        assert_eq!(handlers(&sink.rotated_path(2)).unwrap(), ["a", "b"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
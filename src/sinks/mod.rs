@@ -0,0 +1,4 @@
+pub mod database;
+pub mod file;
+pub mod stdout;
+pub mod syslog;
@@ -0,0 +1,22 @@
+use crate::db::models::HandlerEvent;
+use crate::sink::EventSink;
+use std::io::{self, Write};
+
+/// Prints events to standard output, one JSON object per line
+pub struct StdoutSink;
+
+impl EventSink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn write(&mut self, events: &[HandlerEvent]) -> Result<(), String> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        for event in events {
+            serde_json::to_writer(&mut stdout, event).map_err(|e| e.to_string())?;
+            stdout.write_all(b"\n").map_err(|e| e.to_string())?;
+        }
+        stdout.flush().map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,307 @@
+use crate::db::models::HandlerEvent;
+use crate::sink::EventSink;
+use chrono::{SecondsFormat, Utc};
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+
+/// Private enterprise number reserved for documentation (RFC 5612), used for
+/// the structured data ID
+const SD_ID: &str = "devil@32473";
+/// Severity of every message: informational
+const SEVERITY: u8 = 6;
+/// Largest datagram sent, the default maximum message size of rsyslog.
+/// Longer messages are truncated rather than refused with EMSGSIZE.
+const MAX_DATAGRAM_SIZE: usize = 8192;
+/// How long to wait for the TCP connection, and for each write on it. The
+/// writer thread is shared with the other sinks, which wait meanwhile.
+const TCP_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to drop events after failing to connect, before trying again
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Unix,
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Unix(UnixDatagram),
+}
+
+/// Sends events as RFC 5424 messages, with the main fields as structured
+/// data and the whole event as JSON in the message. Over TCP, messages are
+/// framed with octet counting (RFC 6587).
+pub struct SyslogSink {
+    transport: Transport,
+    address: String,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    connection: Option<Connection>,
+    next_attempt: Instant,
+}
+
+impl SyslogSink {
+    pub fn new(transport: Transport, address: &str, facility: u8, app_name: &str) -> Self {
+        SyslogSink {
+            transport,
+            address: address.to_string(),
+            facility,
+            hostname: std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|hostname| header_field(hostname.trim(), 255))
+                .unwrap_or_else(|_| String::from("-")),
+            app_name: header_field(app_name, 48),
+            connection: None,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        match self.transport {
+            Transport::Udp => {
+                let mut last_error = None;
+                for address in self.address.to_socket_addrs()? {
+                    // The local socket must be of the same family as the address
+                    let local = if address.is_ipv6() {
+                        "[::]:0"
+                    } else {
+                        "0.0.0.0:0"
+                    };
+                    match UdpSocket::bind(local).and_then(|socket| {
+                        socket.connect(address)?;
+                        Ok(socket)
+                    }) {
+                        Ok(socket) => return Ok(Connection::Udp(socket)),
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "address resolves to nothing")
+                }))
+            }
+            Transport::Tcp => {
+                let mut last_error = None;
+                for address in self.address.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&address, TCP_TIMEOUT) {
+                        Ok(stream) => {
+                            stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+                            return Ok(Connection::Tcp(stream));
+                        }
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "address resolves to nothing")
+                }))
+            }
+            Transport::Unix => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&self.address)?;
+                Ok(Connection::Unix(socket))
+            }
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        if self.connection.is_none() {
+            if Instant::now() < self.next_attempt {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "not connected, waiting to retry",
+                ));
+            }
+            match self.connect() {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    self.next_attempt = Instant::now() + RETRY_INTERVAL;
+                    return Err(e);
+                }
+            }
+        }
+        let result = match self.connection.as_mut().expect("Connection was just set") {
+            Connection::Udp(socket) => socket.send(truncate(message)).map(|_| ()),
+            Connection::Tcp(stream) => {
+                let mut frame = format!("{} ", message.len()).into_bytes();
+                frame.extend_from_slice(message);
+                stream.write_all(&frame)
+            }
+            Connection::Unix(socket) => socket.send(truncate(message)).map(|_| ()),
+        };
+        if result.is_err() {
+            // Reconnect on the next message
+            self.connection = None;
+        }
+        result
+    }
+
+    fn format(&self, event: &HandlerEvent) -> Result<Vec<u8>, String> {
+        let src_ip = event.src_ip.map(|ip| ip.ip().to_string());
+        let params = [
+            ("handler", Some(event.handler.as_str())),
+            ("src_ip", src_ip.as_deref()),
+            ("method", event.method.as_deref()),
+            ("uri", event.uri.as_deref()),
+            ("user_agent", event.user_agent.as_deref()),
+        ];
+        let structured_data = params
+            .into_iter()
+            .filter_map(|(name, value)| {
+                value.map(|value| format!(" {}=\"{}\"", name, escape_param(value)))
+            })
+            .collect::<String>();
+
+        let mut message = format!(
+            "<{}>1 {} {} {} {} {} [{}{}] ",
+            self.facility * 8 + SEVERITY,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            header_field(&event.handler, 32),
+            SD_ID,
+            structured_data
+        )
+        .into_bytes();
+        serde_json::to_writer(&mut message, event).map_err(|e| e.to_string())?;
+        Ok(message)
+    }
+}
+
+impl EventSink for SyslogSink {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    /// Sends every event of the batch, even after a failure
+    fn write(&mut self, events: &[HandlerEvent]) -> Result<(), String> {
+        let mut failed = 0;
+        let mut last_error = None;
+        for event in events {
+            let result = self
+                .format(event)
+                .and_then(|message| self.send(&message).map_err(|e| e.to_string()));
+            if let Err(e) = result {
+                failed += 1;
+                last_error = Some(e);
+            }
+        }
+        match last_error {
+            None => Ok(()),
+            Some(e) => Err(format!(
+                "failed to send {} of {} events to {}: {}",
+                failed,
+                events.len(),
+                self.address,
+                e
+            )),
+        }
+    }
+}
+
+/// Cuts a message down to the largest datagram sent, on a character
+/// boundary
+fn truncate(message: &[u8]) -> &[u8] {
+    if message.len() <= MAX_DATAGRAM_SIZE {
+        return message;
+    }
+    let mut end = MAX_DATAGRAM_SIZE;
+    // Continuation bytes of UTF-8 sequences are 0b10xxxxxx
+    while end > 0 && message[end] & 0xc0 == 0x80 {
+        end -= 1;
+    }
+    &message[..end]
+}
+
+/// Header fields are limited to printable ASCII without spaces, with a
+/// maximum length; the nil value `-` is used if nothing is left
+fn header_field(value: &str, max_len: usize) -> String {
+    let field = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect::<String>();
+    if field.is_empty() {
+        String::from("-")
+    } else {
+        field
+    }
+}
+
+fn escape_param(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_on_character_boundaries() {
+        assert_eq!(truncate(b"short"), b"short");
+        let mut message = vec![b'a'; MAX_DATAGRAM_SIZE - 1];
+        message.extend_from_slice("é and more".as_bytes());
+        assert_eq!(truncate(&message).len(), MAX_DATAGRAM_SIZE - 1);
+        message.insert(0, b'a');
+        assert_eq!(truncate(&message).len(), MAX_DATAGRAM_SIZE);
+    }
+
+    #[test]
+    fn sends_whole_batch_as_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let address = receiver.local_addr().unwrap().to_string();
+        let mut sink = SyslogSink::new(Transport::Udp, &address, 16, "devil");
+
+        let large = HandlerEvent::new("large").set_handler_data(Some("x".repeat(70000)));
+        let events = [large, HandlerEvent::new("small")];
+        assert_eq!(sink.write(&events), Ok(()));
+
+        let mut buf = [0; 65536];
+        let size = receiver.recv(&mut buf).unwrap();
+        assert_eq!(size, MAX_DATAGRAM_SIZE);
+        assert!(buf.starts_with(b"<134>1 "));
+        let size = receiver.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..size]);
+        assert!(message.contains("[devil@32473 handler=\"small\"]"));
+    }
+
+    #[test]
+    fn sends_to_ipv6_addresses() {
+        let receiver = match UdpSocket::bind("[::1]:0") {
+            Ok(receiver) => receiver,
+            // No IPv6 loopback to test with
+            Err(_) => return,
+        };
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let address = receiver.local_addr().unwrap().to_string();
+        let mut sink = SyslogSink::new(Transport::Udp, &address, 16, "devil");
+        assert_eq!(sink.write(&[HandlerEvent::new("ipv6")]), Ok(()));
+
+        let mut buf = [0; 65536];
+        let size = receiver.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..size]);
+        assert!(message.contains("[devil@32473 handler=\"ipv6\"]"));
+    }
+
+    #[test]
+    fn counts_failed_events() {
+        let mut sink = SyslogSink::new(Transport::Unix, "/nonexistent/log", 16, "devil");
+        let events = [HandlerEvent::new("a"), HandlerEvent::new("b")];
+        let error = sink.write(&events).unwrap_err();
+        assert!(error.starts_with("failed to send 2 of 2 events to /nonexistent/log"));
+    }
+}
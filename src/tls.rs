@@ -0,0 +1,145 @@
+use crate::configuration::TlsConfig;
+use crate::fingerprint::ClientHello;
+use chrono::{Datelike, Duration, Utc};
+use log::{debug, info};
+use rcgen::{Certificate as GeneratedCertificate, CertificateParams, DistinguishedName, DnType};
+use rustls::internal::pemfile;
+use rustls::{Certificate, NoClientAuth, PrivateKey, ProtocolVersion, ServerConfig, ServerSession};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+// TLS termination for listeners, with rustls. Listeners without a configured
+// certificate get a self-signed one, generated at startup.
+
+/// What was negotiated during the TLS handshake of a connection
+#[derive(Clone)]
+pub struct TlsMetadata {
+    /// Server name requested by the client (SNI)
+    pub sni: Option<String>,
+    /// Application protocol negotiated with ALPN
+    pub alpn: Option<String>,
+    pub version: Option<String>,
+    /// JA3 and JA4 fingerprints of the ClientHello
+    pub ja3: Option<String>,
+    pub ja4: Option<String>,
+}
+
+impl TlsMetadata {
+    /// Collects the parameters of a completed handshake. `client_hello` is
+    /// what the client sent first, starting with its ClientHello record.
+    pub fn new(session: &ServerSession, client_hello: &[u8]) -> Self {
+        use rustls::Session;
+
+        let client_hello = ClientHello::parse(client_hello);
+        if client_hello.is_none() {
+            debug!("Failed to parse ClientHello for fingerprinting");
+        }
+        TlsMetadata {
+            sni: session.get_sni_hostname().map(String::from),
+            alpn: session
+                .get_alpn_protocol()
+                .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+            version: session.get_protocol_version().map(|version| {
+                match version {
+                    ProtocolVersion::TLSv1_3 => "TLSv1.3",
+                    ProtocolVersion::TLSv1_2 => "TLSv1.2",
+                    ProtocolVersion::TLSv1_1 => "TLSv1.1",
+                    ProtocolVersion::TLSv1_0 => "TLSv1.0",
+                    _ => "unknown",
+                }
+                .to_string()
+            }),
+            ja3: client_hello.as_ref().map(ClientHello::ja3),
+            ja4: client_hello.as_ref().map(ClientHello::ja4),
+        }
+    }
+}
+
+fn load_certificates(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("failed to open \"{}\": {}", path, e))?;
+    match pemfile::certs(&mut BufReader::new(file)) {
+        Ok(certificates) if !certificates.is_empty() => Ok(certificates),
+        _ => Err(format!("no PEM certificate found in \"{}\"", path)),
+    }
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+    let read_keys = |parse: fn(&mut dyn std::io::BufRead) -> Result<Vec<PrivateKey>, ()>| {
+        let file = File::open(path).map_err(|e| format!("failed to open \"{}\": {}", path, e))?;
+        Ok::<_, String>(parse(&mut BufReader::new(file)).unwrap_or_default())
+    };
+    read_keys(pemfile::pkcs8_private_keys)?
+        .into_iter()
+        .chain(read_keys(pemfile::rsa_private_keys)?)
+        .next()
+        .ok_or_else(|| format!("no PEM private key found in \"{}\"", path))
+}
+
+/// Generates a self-signed certificate for `common_name` and the subject
+/// alternative names. Its validity starts a few weeks back, like that of a
+/// certificate renewed by an ordinary deployment.
+fn generate_certificate(tls: &TlsConfig) -> Result<(Vec<Certificate>, PrivateKey), String> {
+    let subject_alt_names = if tls.subject_alt_names.is_empty() {
+        vec![tls.common_name.clone()]
+    } else {
+        tls.subject_alt_names.clone()
+    };
+    let mut params = CertificateParams::new(subject_alt_names);
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, tls.common_name.as_str());
+    if let Some(organization) = &tls.organization {
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, organization.as_str());
+    }
+    let not_before = Utc::now() - Duration::days(23);
+    let not_after = not_before + Duration::days(365);
+    params.not_before = rcgen::date_time_ymd(
+        not_before.year(),
+        not_before.month() as u8,
+        not_before.day() as u8,
+    );
+    params.not_after = rcgen::date_time_ymd(
+        not_after.year(),
+        not_after.month() as u8,
+        not_after.day() as u8,
+    );
+
+    let certificate = GeneratedCertificate::from_params(params)
+        .map_err(|e| format!("failed to generate certificate: {}", e))?;
+    let der = certificate
+        .serialize_der()
+        .map_err(|e| format!("failed to serialize certificate: {}", e))?;
+    Ok((
+        vec![Certificate(der)],
+        PrivateKey(certificate.serialize_private_key_der()),
+    ))
+}
+
+/// Builds the rustls configuration of a listener, offering HTTP/2 and
+/// HTTP/1.1 with ALPN
+pub fn server_config(listener: &str, tls: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let (certificates, private_key) = match (&tls.certificate, &tls.private_key) {
+        (Some(certificate), Some(private_key)) => (
+            load_certificates(certificate)?,
+            load_private_key(private_key)?,
+        ),
+        _ => {
+            info!(
+                "Generating a self-signed certificate for \"{}\" on listener \"{}\"",
+                tls.common_name, listener
+            );
+            generate_certificate(tls)?
+        }
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certificates, private_key)
+        .map_err(|e| format!("invalid certificate or private key: {}", e))?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(Arc::new(config))
+}
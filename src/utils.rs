@@ -1,3 +1,4 @@
+use actix_web::http::{Uri, Version};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
@@ -8,3 +9,12 @@ pub fn generate_random_string(length: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+/// Request target as it appears in the request line. HTTP/2 has no request
+/// line, and the URI of its requests includes the scheme and authority.
+pub fn request_target(uri: &Uri, version: Version) -> String {
+    match uri.path_and_query() {
+        Some(target) if version == Version::HTTP_2 => target.to_string(),
+        _ => uri.to_string(),
+    }
+}
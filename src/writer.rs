@@ -0,0 +1,122 @@
+use crate::db::models::HandlerEvent;
+use crate::sink::FilteredSink;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// What to do with an event when the queue is full
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNew,
+}
+
+/// Bounded queue of events waiting to be written to the sinks. Pushing
+/// never blocks on a sink, so request handling is not slowed down by
+/// them; when the queue is full, events are dropped according to the
+/// overflow policy.
+pub struct EventQueue {
+    events: Mutex<VecDeque<HandlerEvent>>,
+    available: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        EventQueue {
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            available: Condvar::new(),
+            capacity,
+            policy,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<HandlerEvent>> {
+        self.events.lock().unwrap_or_else(|e| {
+            error!("Failed to acquire event queue lock: {}", e);
+            std::process::abort();
+        })
+    }
+
+    pub fn push(&self, event: HandlerEvent) {
+        let mut events = self.lock();
+        if events.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    events.pop_front();
+                }
+                OverflowPolicy::DropNew => return,
+            }
+        }
+        events.push_back(event);
+        drop(events);
+        self.available.notify_one();
+    }
+
+    /// Number of events dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits until `max` events are queued or `timeout` has passed, and
+    /// takes up to `max` events from the queue
+    fn take_batch(&self, max: usize, timeout: Duration) -> Vec<HandlerEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut events = self.lock();
+        while events.len() < max {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            events = self
+                .available
+                .wait_timeout(events, deadline - now)
+                .unwrap_or_else(|e| {
+                    error!("Failed to wait on event queue: {}", e);
+                    std::process::abort();
+                })
+                .0;
+        }
+        let count = events.len().min(max);
+        events.drain(..count).collect()
+    }
+}
+
+/// Writes queued events to every sink in batches, until the process exits.
+/// Runs on its own thread, as sinks may block.
+pub fn run_writer(
+    queue: &EventQueue,
+    mut sinks: Vec<FilteredSink>,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    info!("Starting event writer thread");
+    let mut reported_dropped = 0;
+    loop {
+        let batch = queue.take_batch(batch_size, flush_interval);
+
+        let dropped = queue.dropped();
+        if dropped > reported_dropped {
+            warn!(
+                "Event queue full, dropped {} events ({} in total)",
+                dropped - reported_dropped,
+                dropped
+            );
+            reported_dropped = dropped;
+        }
+
+        for sink in sinks.iter_mut() {
+            if let Err(e) = sink.write(&batch) {
+                error!("Failed to write events to {} sink: {}", sink.name(), e);
+            }
+        }
+    }
+}